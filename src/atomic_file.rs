@@ -0,0 +1,16 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use eyre::{Result, WrapErr};
+use std::io::Write;
+
+/// Write to a temporary file then rename it into place, so readers never see a partial file
+#[culpa::try_fn]
+pub(crate) fn write(path: &Utf8Path, contents: &[u8]) -> Result<()> {
+    let temporary = Utf8PathBuf::from(format!("{path}.tmp"));
+    let mut file = std::fs::File::create(&temporary)
+        .wrap_err_with(|| format!("failed to create {temporary}"))?;
+    file.write_all(contents)?;
+    // otherwise a crash soon after the rename can leave an empty file in place
+    file.sync_all()?;
+    std::fs::rename(&temporary, path)
+        .wrap_err_with(|| format!("failed to rename {temporary} to {path}"))?;
+}
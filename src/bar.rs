@@ -0,0 +1,239 @@
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashSet},
+    fmt::Write as _,
+    io::Write,
+    sync::Arc,
+};
+
+use crate::{
+    event,
+    template::{Placeholders, Template},
+};
+
+#[derive(confique::Config, Debug)]
+#[config(partial_attr(derive(Clone, Debug)))]
+#[config(partial_attr(serde(deny_unknown_fields, rename_all = "kebab-case")))]
+pub struct Config {
+    /// Enable module, writes a line to stdout each time the state changes so it can be used as
+    /// a status bar module command
+    #[config(default = false)]
+    pub enable: bool,
+
+    /// Line format, either "waybar" (JSON for a custom module, with `class` and `alt` set to the
+    /// state) or "text" (plain text for i3blocks or polybar)
+    #[config(default = "waybar")]
+    format: Format,
+
+    /// While no device is connected, by default nothing is shown
+    #[config(nested)]
+    absent: StateConfig,
+
+    /// While devices are connected but none are waiting, by default only the icon "🔑" is shown
+    #[config(nested)]
+    idle: StateConfig,
+
+    /// While a device is waiting for a touch, by default the icon "👆" and number of devices
+    /// waiting are shown
+    #[config(nested)]
+    waiting: StateConfig,
+}
+
+#[derive(confique::Config, Debug)]
+#[config(partial_attr(derive(Clone, Debug)))]
+#[config(partial_attr(serde(deny_unknown_fields, rename_all = "kebab-case")))]
+pub struct StateConfig {
+    /// Override the text, supports placeholders `{icon}`, `{pending}` (number of devices
+    /// waiting), `{devices}` (number of devices connected) and `{serials}` (serials of the
+    /// devices waiting)
+    text: Option<Template<Placeholder>>,
+
+    /// Override the tooltip (waybar only), supports the same placeholders as `text`
+    tooltip: Option<Template<Placeholder>>,
+
+    /// Override the icon
+    icon: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Format {
+    Waybar,
+    Text,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Absent,
+    Idle,
+    Waiting,
+}
+
+impl State {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Absent => "absent",
+            Self::Idle => "idle",
+            Self::Waiting => "waiting",
+        }
+    }
+
+    fn defaults(self) -> (&'static str, &'static str, &'static str) {
+        match self {
+            Self::Absent => ("", "No U2F devices connected", ""),
+            Self::Idle => ("{icon}", "{devices} U2F devices connected", "🔑"),
+            Self::Waiting => ("{icon} {pending}", "Touch needed on {serials}", "👆"),
+        }
+    }
+}
+
+/// How a state is shown, with the defaults filled in for anything not overridden
+#[derive(Debug)]
+struct Look {
+    text: Template<Placeholder>,
+    tooltip: Template<Placeholder>,
+    icon: String,
+}
+
+impl Look {
+    #[culpa::try_fn]
+    fn new(state: State, overrides: &StateConfig) -> Result<Self> {
+        let (text, tooltip, icon) = state.defaults();
+        Self {
+            text: match &overrides.text {
+                Some(text) => text.clone(),
+                None => Template::try_from(text.to_owned())?,
+            },
+            tooltip: match &overrides.tooltip {
+                Some(tooltip) => tooltip.clone(),
+                None => Template::try_from(tooltip.to_owned())?,
+            },
+            icon: overrides.icon.clone().unwrap_or_else(|| icon.to_owned()),
+        }
+    }
+}
+
+/// Values available to be substituted into the bar templates
+#[derive(Debug)]
+pub(crate) struct Context<'a> {
+    icon: &'a str,
+    pending: &'a BTreeSet<Arc<str>>,
+    devices: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Placeholder {
+    Icon,
+    Pending,
+    Devices,
+    Serials,
+}
+
+impl Placeholders for Placeholder {
+    type Context<'a> = Context<'a>;
+
+    const ALL: &'static [(&'static str, Self)] = &[
+        ("icon", Self::Icon),
+        ("pending", Self::Pending),
+        ("devices", Self::Devices),
+        ("serials", Self::Serials),
+    ];
+
+    fn render(self, out: &mut String, context: &Context<'_>) {
+        let _ = match self {
+            Self::Icon => write!(out, "{}", context.icon),
+            Self::Pending => write!(out, "{}", context.pending.len()),
+            Self::Devices => write!(out, "{}", context.devices),
+            Self::Serials => {
+                let serials: Vec<_> = context.pending.iter().map(|s| &**s).collect();
+                write!(out, "{}", serials.join(", "))
+            }
+        };
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Waybar<'a> {
+    text: &'a str,
+    tooltip: &'a str,
+    class: &'a str,
+    alt: &'a str,
+}
+
+#[culpa::try_fn]
+pub(crate) fn run(config: Config, mut rx: event::Receiver) -> Result<()> {
+    let absent = Look::new(State::Absent, &config.absent)?;
+    let idle = Look::new(State::Idle, &config.idle)?;
+    let waiting = Look::new(State::Waiting, &config.waiting)?;
+
+    let mut devices = HashSet::new();
+    let mut pending = BTreeSet::new();
+    let mut last = None;
+
+    loop {
+        let state = match (devices.is_empty(), pending.is_empty()) {
+            (_, false) => State::Waiting,
+            (true, true) => State::Absent,
+            (false, true) => State::Idle,
+        };
+
+        let look = match state {
+            State::Absent => &absent,
+            State::Idle => &idle,
+            State::Waiting => &waiting,
+        };
+        let line = render(config.format, state, look, &pending, devices.len())?;
+        if last.as_ref() != Some(&line) {
+            let mut stdout = std::io::stdout().lock();
+            writeln!(stdout, "{line}")?;
+            stdout.flush()?;
+            last = Some(line);
+        }
+
+        let Some(event) = event::recv(&mut rx) else {
+            break;
+        };
+        let id = event.device().id();
+        match event {
+            event::Event::DeviceAdded { .. } => {
+                devices.insert(id.clone());
+            }
+            event::Event::DeviceRemoved { .. } => {
+                devices.remove(&id);
+            }
+            _ => {}
+        }
+        match event.needed() {
+            Some(true) => pending.insert(id),
+            Some(false) => pending.remove(&id),
+            None => false,
+        };
+    }
+}
+
+#[culpa::try_fn]
+fn render(
+    format: Format,
+    state: State,
+    look: &Look,
+    pending: &BTreeSet<Arc<str>>,
+    devices: usize,
+) -> Result<String> {
+    let context = Context {
+        icon: &look.icon,
+        pending,
+        devices,
+    };
+    let (text, tooltip) = (look.text.render(&context), look.tooltip.render(&context));
+
+    match format {
+        Format::Waybar => serde_json::to_string(&Waybar {
+            text: &text,
+            tooltip: &tooltip,
+            class: state.name(),
+            alt: state.name(),
+        })?,
+        Format::Text => text,
+    }
+}
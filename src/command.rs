@@ -1,5 +1,5 @@
 use eyre::{Error, OptionExt, Result};
-use zerocopy::{FromBytes, FromZeroes};
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
 #[derive(FromZeroes, FromBytes, PartialEq, Eq, Copy, Clone)]
 #[repr(transparent)]
@@ -23,13 +23,19 @@ impl std::fmt::Debug for Status {
     }
 }
 
+// https://fidoalliance.org/specs/fido-v2.0-ps-20190130/fido-client-to-authenticator-protocol-v2.0-ps-20190130.html#error-responses
+pub(crate) const CTAP2_OK: u8 = 0x00;
+pub(crate) const CTAP2_ERR_OPERATION_DENIED: u8 = 0x27;
+pub(crate) const CTAP2_ERR_KEEPALIVE_CANCEL: u8 = 0x2d;
+pub(crate) const CTAP2_ERR_USER_ACTION_TIMEOUT: u8 = 0x2f;
+
 #[derive(FromZeroes, FromBytes, Debug)]
 #[repr(C)]
 pub(crate) struct KeepAlive {
     pub(crate) status: Status,
 }
 
-#[derive(FromZeroes, FromBytes, PartialEq, Eq, Copy, Clone)]
+#[derive(FromZeroes, FromBytes, AsBytes, PartialEq, Eq, Copy, Clone)]
 #[repr(transparent)]
 pub(crate) struct Kind(u8);
 
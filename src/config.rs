@@ -1,8 +1,8 @@
 use confique::Config as _;
 use directories::ProjectDirs;
 use eyre::{OptionExt, Result};
-use serde::de::{Deserialize, Deserializer};
-use std::collections::BTreeMap;
+use serde::de::{Deserialize, Deserializer, Error as _};
+use std::{collections::BTreeMap, time::Duration};
 
 #[derive(confique::Config, Debug)]
 #[config(partial_attr(derive(Clone, Debug)))]
@@ -11,6 +11,50 @@ pub struct Config {
     /// Desktop notifications module
     #[config(nested)]
     pub notify: crate::notify::Config,
+
+    /// Commands run on events module
+    #[config(nested)]
+    pub exec: crate::exec::Config,
+
+    /// D-Bus session service module
+    #[config(nested)]
+    pub dbus: crate::dbus::Config,
+
+    /// Status bar module
+    #[config(nested)]
+    pub bar: crate::bar::Config,
+
+    /// Tray icon module
+    #[config(nested)]
+    pub tray: crate::tray::Config,
+
+    /// Local web server for browser overlays module
+    #[config(nested)]
+    pub web: crate::web::Config,
+
+    /// Keyboard or other sysfs LED module
+    #[config(nested)]
+    pub led: crate::led::Config,
+
+    /// MQTT home automation module
+    #[config(nested)]
+    pub mqtt: crate::mqtt::Config,
+
+    /// OpenRGB keyboard lighting module
+    #[config(nested)]
+    pub openrgb: crate::openrgb::Config,
+
+    /// Push notifications through ntfy or Gotify module
+    #[config(nested)]
+    pub push: crate::push::Config,
+
+    /// State file for scripts module
+    #[config(nested)]
+    pub state_file: crate::state_file::Config,
+
+    /// Prometheus metrics module
+    #[config(nested)]
+    pub prometheus: crate::prometheus::Config,
 }
 
 pub type Partial = <Config as confique::Config>::Partial;
@@ -32,6 +76,21 @@ impl Config {
     }
 }
 
+/// Deserialize a human readable duration like `300ms` or `15m`
+pub fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    humantime::parse_duration(&value).map_err(D::Error::custom)
+}
+
+/// Deserialize a duration like [`duration`] that must not be zero, e.g. an interval to repeat at
+pub fn positive_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let value = duration(deserializer)?;
+    if value.is_zero() {
+        return Err(D::Error::custom("duration must be more than zero"));
+    }
+    Ok(value)
+}
+
 #[derive(Debug)]
 pub struct ConfigMap<V: confique::Config> {
     pub inner: BTreeMap<String, V>,
@@ -0,0 +1,319 @@
+use eyre::Result;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    time::Duration,
+};
+use tracing::{info, warn};
+use zbus::{fdo, object_server::SignalContext};
+
+use crate::{
+    device,
+    event::{self, Event},
+    metrics,
+};
+
+const PATH: &str = "/io/github/Nemo157/U2fTouchDetector";
+
+/// Longest a device can be snoozed for
+const MAX_SNOOZE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(confique::Config, Debug)]
+#[config(partial_attr(derive(Clone, Debug)))]
+#[config(partial_attr(serde(deny_unknown_fields, rename_all = "kebab-case")))]
+pub struct Config {
+    /// Enable module
+    #[config(default = false)]
+    pub enable: bool,
+
+    /// Well-known name to own on the session bus, the object is served at
+    /// `/io/github/Nemo157/U2fTouchDetector`
+    #[config(default = "io.github.Nemo157.U2fTouchDetector")]
+    name: String,
+}
+
+struct Service {
+    devices: BTreeMap<Arc<str>, Arc<device::Info>>,
+    pending: BTreeSet<Arc<str>>,
+    /// For asking the notify output to snooze a device
+    tx: event::Sender,
+    /// Whether the notify output is running to handle snoozing
+    snooze_supported: bool,
+}
+
+impl Service {
+    fn new(tx: event::Sender, snooze_supported: bool) -> Self {
+        Self {
+            devices: BTreeMap::new(),
+            pending: BTreeSet::new(),
+            tx,
+            snooze_supported,
+        }
+    }
+
+    fn device(&self, id: &str) -> fdo::Result<&Arc<device::Info>> {
+        self.devices
+            .get(id)
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("unknown device {id:?}")))
+    }
+}
+
+#[zbus::interface(name = "io.github.Nemo157.U2fTouchDetector")]
+impl Service {
+    /// Ids of the devices waiting for a touch, a device's id is its serial, or without one its
+    /// vendor id, product id and hidraw device, e.g. `1050_0407_hidraw0`
+    #[zbus(property)]
+    fn pending(&self) -> Vec<String> {
+        self.pending.iter().map(|id| id.to_string()).collect()
+    }
+
+    /// Connected devices, as (id, manufacturer, product, hidraw path, vendor id, product id)
+    #[zbus(property)]
+    fn devices(&self) -> Vec<(String, String, String, String, u16, u16)> {
+        self.devices
+            .values()
+            .map(|device| {
+                (
+                    device.id().to_string(),
+                    device.manufacturer.clone(),
+                    device.product.clone(),
+                    device.path.to_string(),
+                    device.vendor_id,
+                    device.product_id,
+                )
+            })
+            .collect()
+    }
+
+    /// Suppress notifications for the device for `seconds`, at most a week
+    fn snooze(&self, id: &str, seconds: u64) -> fdo::Result<()> {
+        if !self.snooze_supported {
+            return Err(fdo::Error::NotSupported(
+                "snoozing needs the notify output enabled".to_owned(),
+            ));
+        }
+        let device = self.device(id)?.clone();
+        let duration = Duration::from_secs(seconds);
+        if duration > MAX_SNOOZE {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "cannot snooze for longer than {} seconds",
+                MAX_SNOOZE.as_secs()
+            )));
+        }
+        // we hold a receiver ourselves, so this can't fail
+        let _ = self.tx.send(Event::SnoozeRequested { device, duration });
+        Ok(())
+    }
+
+    /// Ask the device to identify itself, e.g. by flashing its LED
+    async fn identify(&self, id: &str) -> fdo::Result<()> {
+        let device = self.device(id)?.clone();
+        // winking blocks for up to a second, which would hold up every other method call
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        std::thread::spawn(move || tx.send(device.wink()));
+        rx.await
+            .map_err(|_| fdo::Error::Failed("identify thread panicked".to_owned()))?
+            .map_err(|err| fdo::Error::Failed(format!("{err:#}")))
+    }
+
+    #[zbus(signal)]
+    async fn touch_needed(ctxt: &SignalContext<'_>, id: &str) -> zbus::Result<()>;
+
+    /// `outcome` is one of "touched", "timed-out", "denied", "cancelled" or "unknown", and
+    /// `duration` how many seconds the device was waiting
+    #[zbus(signal)]
+    async fn touch_finished(
+        ctxt: &SignalContext<'_>,
+        id: &str,
+        outcome: &str,
+        duration: f64,
+    ) -> zbus::Result<()>;
+}
+
+#[culpa::try_fn]
+pub(crate) fn run(
+    config: Config,
+    rx: event::Receiver,
+    tx: event::Sender,
+    snooze_supported: bool,
+) -> Result<()> {
+    let connection = zbus::blocking::connection::Builder::session()?
+        .name(config.name.as_str())?
+        .serve_at(PATH, Service::new(tx, snooze_supported))?
+        .build()?;
+    info!(name = config.name, "serving on the session bus");
+    forward(&connection, rx)?;
+}
+
+/// Update the served properties and emit signals for each event
+#[culpa::try_fn]
+fn forward(connection: &zbus::blocking::Connection, mut rx: event::Receiver) -> Result<()> {
+    let iface = connection.object_server().interface::<_, Service>(PATH)?;
+    let ctxt = iface.signal_context();
+
+    while let Some(event) = event::recv(&mut rx) {
+        let mut service = iface.get_mut();
+        let device = event.device();
+        let result = zbus::block_on(async {
+            match &event {
+                Event::TouchNeeded { .. } => {
+                    service.pending.insert(device.id());
+                    service.pending_changed(ctxt).await?;
+                    Service::touch_needed(ctxt, &device.id()).await?;
+                }
+                Event::TouchFinished {
+                    outcome, duration, ..
+                } => {
+                    service.pending.remove(&device.id());
+                    service.pending_changed(ctxt).await?;
+                    let duration = duration.as_secs_f64();
+                    Service::touch_finished(ctxt, &device.id(), outcome.name(), duration).await?;
+                }
+                Event::DeviceAdded { .. } => {
+                    service.devices.insert(device.id(), device.clone());
+                    service.devices_changed(ctxt).await?;
+                }
+                Event::DeviceRemoved { .. } => {
+                    service.devices.remove(&device.id());
+                    service.devices_changed(ctxt).await?;
+                    if service.pending.remove(&device.id()) {
+                        service.pending_changed(ctxt).await?;
+                    }
+                }
+                Event::SnoozeRequested { .. } => {}
+            }
+            zbus::Result::Ok(())
+        });
+        if let Err(err) = result {
+            metrics::OUTPUT_FAILURES.increment(&["dbus"]);
+            warn!("failed to emit D-Bus signal: {err:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::testing::{self, touch_finished, touch_needed};
+
+    /// Serve the service over a private connection rather than the session bus, returning a
+    /// connection to call it on
+    fn serve(service: Service, rx: event::Receiver) -> zbus::blocking::Connection {
+        let (client, server) = std::os::unix::net::UnixStream::pair().unwrap();
+        std::thread::spawn(move || {
+            let server = zbus::blocking::connection::Builder::unix_stream(server)
+                .server(zbus::Guid::generate())
+                .unwrap()
+                .p2p()
+                .serve_at(PATH, service)
+                .unwrap()
+                .build()
+                .unwrap();
+            forward(&server, rx)
+        });
+        zbus::blocking::connection::Builder::unix_stream(client)
+            .p2p()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn properties_signals_and_snooze() {
+        let (events, rx) = tokio::sync::broadcast::channel(16);
+        let mut snoozes = events.subscribe();
+        let client = serve(Service::new(events.clone(), true), rx);
+        let proxy = zbus::blocking::proxy::Builder::<zbus::blocking::Proxy<'_>>::new(&client)
+            .destination("io.github.Nemo157.U2fTouchDetector")
+            .unwrap()
+            .path(PATH)
+            .unwrap()
+            .interface("io.github.Nemo157.U2fTouchDetector")
+            .unwrap()
+            .cache_properties(zbus::proxy::CacheProperties::No)
+            .build()
+            .unwrap();
+        let rule = zbus::MatchRule::builder()
+            .msg_type(zbus::message::Type::Signal)
+            .interface("io.github.Nemo157.U2fTouchDetector")
+            .unwrap()
+            .build();
+        let mut signals =
+            zbus::blocking::MessageIterator::for_match_rule(rule, &client, None).unwrap();
+        let mut next_signal = || {
+            let message = signals.next().unwrap().unwrap();
+            let member = message.header().member().unwrap().to_string();
+            (member, message)
+        };
+
+        let device = testing::device();
+        events
+            .send(Event::DeviceAdded {
+                device: device.clone(),
+            })
+            .unwrap();
+        events.send(touch_needed(&device)).unwrap();
+
+        let (member, message) = next_signal();
+        assert_eq!(member, "TouchNeeded");
+        assert_eq!(message.body().deserialize::<String>().unwrap(), "12345678");
+        let pending: Vec<String> = proxy.get_property("Pending").unwrap();
+        assert_eq!(pending, ["12345678"]);
+        let devices: Vec<(String, String, String, String, u16, u16)> =
+            proxy.get_property("Devices").unwrap();
+        assert_eq!(
+            devices,
+            [(
+                "12345678".to_owned(),
+                "Yubico".to_owned(),
+                "YubiKey OTP+FIDO+CCID".to_owned(),
+                "/dev/hidraw0".to_owned(),
+                0x1050,
+                0x0407,
+            )]
+        );
+
+        proxy.call_method("Snooze", &("12345678", 60u64)).unwrap();
+        let snooze = std::iter::from_fn(|| snoozes.try_recv().ok())
+            .find(|event| matches!(event, Event::SnoozeRequested { .. }));
+        assert!(
+            matches!(
+                &snooze,
+                Some(Event::SnoozeRequested { device, duration })
+                    if &*device.serial == "12345678" && duration.as_secs() == 60
+            ),
+            "{snooze:?}"
+        );
+        assert!(proxy.call_method("Snooze", &("unknown", 60u64)).is_err());
+        assert!(proxy
+            .call_method("Snooze", &("12345678", MAX_SNOOZE.as_secs() + 1))
+            .is_err());
+
+        events.send(touch_finished(&device)).unwrap();
+        let (member, message) = next_signal();
+        assert_eq!(member, "TouchFinished");
+        let finished: (String, String, f64) = message.body().deserialize().unwrap();
+        assert_eq!(finished, ("12345678".to_owned(), "touched".to_owned(), 1.0));
+        let pending: Vec<String> = proxy.get_property("Pending").unwrap();
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn snooze_needs_notify() {
+        let (events, rx) = tokio::sync::broadcast::channel(16);
+        let client = serve(Service::new(events.clone(), false), rx);
+        let proxy = zbus::blocking::Proxy::new(
+            &client,
+            "io.github.Nemo157.U2fTouchDetector",
+            PATH,
+            "io.github.Nemo157.U2fTouchDetector",
+        )
+        .unwrap();
+        let err = proxy
+            .call_method("Snooze", &("12345678", 60u64))
+            .unwrap_err();
+        assert!(
+            matches!(&err, zbus::Error::MethodError(name, ..) if name.as_str() == "org.freedesktop.DBus.Error.NotSupported"),
+            "{err:?}"
+        );
+    }
+}
@@ -1,13 +1,18 @@
 use camino::{Utf8Path, Utf8PathBuf};
-use eyre::{eyre, Result};
+use eyre::{bail, ensure, eyre, OptionExt, Result};
+use serde::Serialize;
 use std::{
+    ffi::CString,
     sync::Arc,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 use tracing::{debug, info, info_span, trace, trace_span};
 
 use crate::command::{self, Command, Status};
+use crate::event::{self, Event, Outcome};
 use crate::message::{Channel, Message, FIDO_CTAPHID_MAX_MESSAGE_SIZE};
+use crate::metrics;
+use crate::packet::Init;
 
 // https://fidoalliance.org/specs/fido-v2.0-ps-20190130/fido-client-to-authenticator-protocol-v2.0-ps-20190130.html#usb-discovery
 const FIDO_USAGE_PAGE: u16 = 0xf1d0;
@@ -22,9 +27,150 @@ const FIDO_USAGE_CTAPHID: u16 = 0x01;
 // state back and forth during a single transaction.
 const HYSTERESIS_DURATION: Duration = std::time::Duration::from_millis(400);
 
-pub(crate) struct Device {
+// https://fidoalliance.org/specs/fido-v2.0-ps-20190130/fido-client-to-authenticator-protocol-v2.0-ps-20190130.html#usb-hid-init
+const CAPABILITY_WINK: u8 = 0x01;
+
+// How long to wait for the device to respond to commands we send it ourselves
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Metadata describing a device, shared with the outputs
+#[derive(Debug, Serialize)]
+pub(crate) struct Info {
     pub(crate) path: Utf8PathBuf,
     pub(crate) serial: Arc<str>,
+    pub(crate) manufacturer: String,
+    pub(crate) product: String,
+    pub(crate) vendor_id: u16,
+    pub(crate) product_id: u16,
+}
+
+#[cfg(test)]
+impl Info {
+    /// A device for tests, without any hardware behind it
+    pub(crate) fn fake(serial: &str) -> Self {
+        Self {
+            path: "/dev/hidraw0".into(),
+            serial: serial.into(),
+            manufacturer: "Yubico".into(),
+            product: "YubiKey OTP+FIDO+CCID".into(),
+            vendor_id: 0x1050,
+            product_id: 0x0407,
+        }
+    }
+}
+
+impl Info {
+    /// Distinguishes the device from others connected, its serial if it has one, otherwise its
+    /// vendor and product ids and hidraw device so devices without serials don't collide
+    pub(crate) fn id(&self) -> Arc<str> {
+        if self.serial.is_empty() {
+            let device = self.path.file_name().unwrap_or_default();
+            format!("{:04x}_{:04x}_{device}", self.vendor_id, self.product_id).into()
+        } else {
+            self.serial.clone()
+        }
+    }
+
+    #[culpa::try_fn]
+    fn open(&self) -> Result<hidapi::HidDevice> {
+        // this is a second handle to the device, on linux the original thread will continue to see
+        // all responses too, but it ignores anything that isn't part of a CBOR request
+        let hidapi = hidapi::HidApi::new_without_enumerate()?;
+        hidapi.open_path(&CString::new(self.path.as_str())?)?
+    }
+
+    /// Ask the device to identify itself, usually by flashing its LED
+    #[culpa::try_fn]
+    pub(crate) fn wink(&self) -> Result<()> {
+        let device = self.open()?;
+        let init = init(&device)?;
+        ensure!(
+            init.capabilities & CAPABILITY_WINK != 0,
+            "device does not support wink"
+        );
+
+        let mut buffer = [0; FIDO_CTAPHID_MAX_MESSAGE_SIZE];
+        Init::new(init.channel, command::Kind::WINK, &[])?.write_to(&device)?;
+        read_response(&device, &mut buffer, init.channel, |_| true)?;
+    }
+
+    /// The firmware version the device reports, as `major.minor.build`
+    #[culpa::try_fn]
+    pub(crate) fn firmware(&self) -> Result<String> {
+        let [major, minor, build] = init(&self.open()?)?.version;
+        format!("{major}.{minor}.{build}")
+    }
+
+    /// Cancel the outstanding request on `channel`
+    #[culpa::try_fn]
+    pub(crate) fn cancel(&self, channel: Channel) -> Result<()> {
+        let device = self.open()?;
+        Init::new(channel, command::Kind::CANCEL, &[])?.write_to(&device)?;
+    }
+}
+
+/// What the device reports when a channel is allocated
+#[derive(Debug)]
+struct InitResponse {
+    channel: Channel,
+    version: [u8; 3],
+    capabilities: u8,
+}
+
+/// Allocate a channel for our own commands
+#[culpa::try_fn]
+fn init(device: &hidapi::HidDevice) -> Result<InitResponse> {
+    let mut buffer = [0; FIDO_CTAPHID_MAX_MESSAGE_SIZE];
+
+    let nonce = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_nanos()
+        .to_le_bytes();
+    let nonce = &nonce[..8];
+
+    Init::new(Channel::BROADCAST, command::Kind::INIT, nonce)?.write_to(device)?;
+    let payload = read_response(device, &mut buffer, Channel::BROADCAST, |payload| {
+        payload.starts_with(nonce)
+    })?;
+
+    // nonce, channel id, protocol version, major, minor, build, capabilities
+    ensure!(payload.len() >= 17, "init response too short");
+    InitResponse {
+        channel: Channel(payload[8..12].try_into().unwrap()),
+        version: payload[13..16].try_into().unwrap(),
+        capabilities: payload[16],
+    }
+}
+
+/// Wait for a response on `channel` that `filter` accepts, returning its payload
+#[culpa::try_fn]
+fn read_response<'a>(
+    device: &hidapi::HidDevice,
+    buffer: &'a mut [u8; FIDO_CTAPHID_MAX_MESSAGE_SIZE],
+    channel: Channel,
+    filter: impl Fn(&[u8]) -> bool,
+) -> Result<&'a [u8]> {
+    let deadline = Instant::now() + COMMAND_TIMEOUT;
+    let length = loop {
+        let message = Message::read_from(device, buffer, Some(deadline))?
+            .ok_or_eyre("timed out waiting for response")?;
+        if message.channel != channel {
+            continue;
+        }
+        match message.command {
+            Command::Other {
+                kind: command::Kind::ERROR,
+                payload,
+            } => bail!("device returned error {:#x}", payload.first().unwrap_or(&0)),
+            Command::Other { payload, .. } if filter(payload) => break payload.len(),
+            _ => {}
+        }
+    };
+    &buffer[..length]
+}
+
+pub(crate) struct Device {
+    pub(crate) info: Arc<Info>,
     device: hidapi::HidDevice,
 }
 
@@ -41,29 +187,34 @@ impl Device {
                 return Some(Err(eyre!("device has non-utf8 path: {:?}", info.path())));
             };
 
-            let path = Utf8PathBuf::from(path);
-            let serial = Arc::<str>::from(info.serial_number().unwrap_or_default());
+            let details = Arc::new(Info {
+                path: Utf8PathBuf::from(path),
+                serial: Arc::from(info.serial_number().unwrap_or_default()),
+                manufacturer: info.manufacturer_string().unwrap_or_default().to_owned(),
+                product: info.product_string().unwrap_or_default().to_owned(),
+                vendor_id: info.vendor_id(),
+                product_id: info.product_id(),
+            });
 
             let _guard = info_span!(
                 "device",
-                device.serial = %serial
+                device.serial = %details.serial
             )
             .entered();
 
             debug!(
-                device.manufacturer = info.manufacturer_string().unwrap_or_default(),
-                device.product = info.product_string().unwrap_or_default(),
-                device.id.vendor = format!("{:4x}", info.vendor_id()),
-                device.id.product = format!("{:4x}", info.product_id()),
-                device.path = %path,
+                device.manufacturer = details.manufacturer,
+                device.product = details.product,
+                device.id.vendor = format!("{:4x}", details.vendor_id),
+                device.id.product = format!("{:4x}", details.product_id),
+                device.path = %details.path,
                 "found device"
             );
 
             Some(
                 info.open_device(hidapi)
                     .map(|device| Self {
-                        path,
-                        serial,
+                        info: details,
                         device,
                     })
                     .map_err(eyre::Error::from),
@@ -72,25 +223,34 @@ impl Device {
     }
 
     pub(crate) fn path(&self) -> &Utf8Path {
-        &self.path
+        &self.info.path
     }
 
     #[culpa::try_fn]
-    pub(crate) fn process_messages(
-        &self,
-        tx: tokio::sync::broadcast::Sender<(Arc<str>, bool)>,
-    ) -> Result<()> {
+    pub(crate) fn process_messages(&self, tx: event::Sender) -> Result<()> {
         let mut buffer = [0; FIDO_CTAPHID_MAX_MESSAGE_SIZE];
 
         let mut deadline = None;
+        let mut started = Instant::now();
         let mut channel = Channel([0; 4]);
         loop {
-            let Some(message) = Message::read_from(&self.device, &mut buffer, deadline)? else {
+            let message =
+                Message::read_from(&self.device, &mut buffer, deadline).inspect_err(|err| {
+                    // failing to read is the device going away, anything else is it sending garbage
+                    if err.downcast_ref::<hidapi::HidError>().is_none() {
+                        metrics::PROTOCOL_ERRORS.increment(&[&self.info.serial]);
+                    }
+                })?;
+            let Some(message) = message else {
                 trace!("no response");
                 if deadline.map(|d| Instant::now() >= d).unwrap_or(false) {
                     trace!("hit deadline, assume device gave up");
                     info!("touch no longer needed");
-                    let _ = tx.send((self.serial.clone(), false));
+                    let _ = tx.send(Event::TouchFinished {
+                        device: self.info.clone(),
+                        outcome: Outcome::Unknown,
+                        duration: started.elapsed(),
+                    });
                     deadline = None;
                 }
                 continue;
@@ -103,7 +263,11 @@ impl Device {
                     Status::UPNEEDED => {
                         if deadline.is_none() {
                             info!("touch needed");
-                            let _ = tx.send((self.serial.clone(), true));
+                            started = Instant::now();
+                            let _ = tx.send(Event::TouchNeeded {
+                                device: self.info.clone(),
+                                channel: message.channel,
+                            });
                         }
                         deadline = Some(Instant::now() + HYSTERESIS_DURATION);
                         channel = message.channel;
@@ -120,11 +284,22 @@ impl Device {
                 },
                 Command::Other {
                     kind: command::Kind::CBOR,
-                    ..
+                    payload,
                 } if deadline.is_some() => {
                     trace!("received a response, clearing deadline");
-                    info!("touch no longer needed");
-                    let _ = tx.send((self.serial.clone(), false));
+                    let outcome = match payload.first().copied() {
+                        Some(command::CTAP2_OK) => Outcome::Touched,
+                        Some(command::CTAP2_ERR_USER_ACTION_TIMEOUT) => Outcome::TimedOut,
+                        Some(command::CTAP2_ERR_OPERATION_DENIED) => Outcome::Denied,
+                        Some(command::CTAP2_ERR_KEEPALIVE_CANCEL) => Outcome::Cancelled,
+                        _ => Outcome::Unknown,
+                    };
+                    info!(?outcome, "touch no longer needed");
+                    let _ = tx.send(Event::TouchFinished {
+                        device: self.info.clone(),
+                        outcome,
+                        duration: started.elapsed(),
+                    });
                     deadline = None;
                 }
                 _ => trace!("ignoring unhandled command"),
@@ -0,0 +1,245 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    sync::{mpsc, Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+
+use crate::{device, message::Channel, shutdown};
+
+#[derive(Debug, Clone)]
+pub(crate) enum Event {
+    /// The device is waiting for the user to touch it
+    TouchNeeded {
+        device: Arc<device::Info>,
+        /// The channel of the request waiting for the touch
+        channel: Channel,
+    },
+    /// The device is no longer waiting for a touch
+    TouchFinished {
+        device: Arc<device::Info>,
+        outcome: Outcome,
+        /// How long the device was waiting for
+        duration: Duration,
+    },
+    /// A new device was found
+    DeviceAdded { device: Arc<device::Info> },
+    /// The device was unplugged, or stopped responding
+    DeviceRemoved { device: Arc<device::Info> },
+    /// Something asked for notifications for the device to be suppressed for a while
+    SnoozeRequested {
+        device: Arc<device::Info>,
+        duration: Duration,
+    },
+}
+
+/// Why the device stopped waiting for a touch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Outcome {
+    /// The request completed, so the user must have touched the device
+    Touched,
+    /// The device gave up waiting for the touch
+    TimedOut,
+    /// The device declined the request
+    Denied,
+    /// The client cancelled the request
+    Cancelled,
+    /// The device stopped waiting without us seeing why
+    Unknown,
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Touched => "touched",
+            Self::TimedOut => "timed out",
+            Self::Denied => "denied",
+            Self::Cancelled => "cancelled",
+            Self::Unknown => "ended",
+        })
+    }
+}
+
+impl Outcome {
+    /// The name used in config and for external tools, e.g. `timed-out`
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::Touched => "touched",
+            Self::TimedOut => "timed-out",
+            Self::Denied => "denied",
+            Self::Cancelled => "cancelled",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+impl Event {
+    pub(crate) fn device(&self) -> &Arc<device::Info> {
+        match self {
+            Self::TouchNeeded { device, .. }
+            | Self::TouchFinished { device, .. }
+            | Self::DeviceAdded { device }
+            | Self::DeviceRemoved { device }
+            | Self::SnoozeRequested { device, .. } => device,
+        }
+    }
+
+    /// Whether the device is waiting for a touch after this event, `None` if it doesn't affect that
+    pub(crate) fn needed(&self) -> Option<bool> {
+        match self {
+            Self::TouchNeeded { .. } => Some(true),
+            Self::TouchFinished { .. } | Self::DeviceRemoved { .. } => Some(false),
+            Self::DeviceAdded { .. } | Self::SnoozeRequested { .. } => None,
+        }
+    }
+}
+
+pub(crate) type Sender = tokio::sync::broadcast::Sender<Event>;
+pub(crate) type Receiver = tokio::sync::broadcast::Receiver<Event>;
+
+/// Wait for the next event, skipping over any missed because the output fell behind, `None` once
+/// there are no more events
+pub(crate) fn recv(rx: &mut Receiver) -> Option<Event> {
+    loop {
+        match rx.blocking_recv() {
+            Ok(event) => return Some(event),
+            Err(RecvError::Lagged(count)) => warn!(count, "output fell behind, missed events"),
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+/// Changes to whether any device is waiting for a touch, see `relay_pending`
+pub(crate) struct Pending {
+    changes: mpsc::Receiver<bool>,
+    /// Keeps the process from exiting until the output is done with this
+    _guard: shutdown::Guard,
+}
+
+impl Pending {
+    pub(crate) fn recv(&self) -> Result<bool, mpsc::RecvError> {
+        self.changes.recv()
+    }
+
+    pub(crate) fn recv_timeout(&self, timeout: Duration) -> Result<bool, mpsc::RecvTimeoutError> {
+        self.changes.recv_timeout(timeout)
+    }
+}
+
+/// Follow whether any device is waiting for a touch on a separate thread, sending `true` when the
+/// first device starts waiting and `false` once none are, for outputs that drive a single light
+///
+/// The changes disconnect on shutdown, so the output puts back what it changed, the process waits
+/// until the output drops them before exiting.
+pub(crate) fn relay_pending(mut rx: Receiver) -> Pending {
+    let (tx, changes) = mpsc::channel();
+    let tx = Arc::new(Mutex::new(Some(tx)));
+    let guard = shutdown::OUTPUTS.register({
+        let tx = Arc::downgrade(&tx);
+        move || {
+            if let Some(tx) = tx.upgrade() {
+                tx.lock().unwrap().take();
+            }
+        }
+    });
+    std::thread::spawn(move || {
+        let mut pending = HashSet::new();
+        while let Some(event) = recv(&mut rx) {
+            let Some(needed) = event.needed() else {
+                continue;
+            };
+            let was_pending = !pending.is_empty();
+            let id = event.device().id();
+            if needed {
+                pending.insert(id);
+            } else {
+                pending.remove(&id);
+            }
+            let is_pending = !pending.is_empty();
+            if was_pending != is_pending {
+                let tx = tx.lock().unwrap();
+                if tx.as_ref().map_or(true, |tx| tx.send(is_pending).is_err()) {
+                    break;
+                }
+            }
+        }
+    });
+    Pending {
+        changes,
+        _guard: guard,
+    }
+}
+
+/// Fixtures shared by the output tests
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+    use std::time::Instant;
+
+    /// A device with the serial `12345678`
+    pub(crate) fn device() -> Arc<device::Info> {
+        Arc::new(device::Info::fake("12345678"))
+    }
+
+    /// Run an output on its own thread, returning the sender to feed it events
+    pub(crate) fn spawn<T: Send + 'static>(
+        run: impl FnOnce(Receiver) -> T + Send + 'static,
+    ) -> (Sender, std::thread::JoinHandle<T>) {
+        let (events, rx) = tokio::sync::broadcast::channel(16);
+        (events, std::thread::spawn(move || run(rx)))
+    }
+
+    pub(crate) fn touch_needed(device: &Arc<device::Info>) -> Event {
+        Event::TouchNeeded {
+            device: device.clone(),
+            channel: Channel([0; 4]),
+        }
+    }
+
+    /// Touched after waiting a second
+    pub(crate) fn touch_finished(device: &Arc<device::Info>) -> Event {
+        Event::TouchFinished {
+            device: device.clone(),
+            outcome: Outcome::Touched,
+            duration: Duration::from_secs(1),
+        }
+    }
+
+    /// Wait up to a second for `check` to pass
+    pub(crate) fn eventually(mut check: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while !check() {
+            assert!(Instant::now() < deadline, "timed out");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{testing::*, *};
+
+    #[test]
+    fn relay_pending_tells_devices_without_serials_apart() {
+        let (events, rx) = tokio::sync::broadcast::channel(16);
+        let changes = relay_pending(rx);
+        let first = Arc::new(device::Info::fake(""));
+        let second = Arc::new(device::Info {
+            path: "/dev/hidraw1".into(),
+            ..device::Info::fake("")
+        });
+        assert_ne!(first.id(), second.id());
+
+        events.send(touch_needed(&first)).unwrap();
+        events.send(touch_needed(&second)).unwrap();
+        assert_eq!(changes.recv(), Ok(true));
+
+        events.send(touch_finished(&first)).unwrap();
+        assert!(changes.recv_timeout(Duration::from_millis(100)).is_err());
+        events.send(touch_finished(&second)).unwrap();
+        assert_eq!(changes.recv(), Ok(false));
+    }
+}
@@ -0,0 +1,239 @@
+use crate::{
+    device,
+    event::{self, Event},
+    metrics,
+};
+use eyre::Result;
+use std::{
+    collections::HashSet,
+    io::{BufRead, BufReader, Read},
+    os::unix::process::CommandExt,
+    process::{Child, Command, Stdio},
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, info_span, warn, Span};
+
+/// How often to check whether a running command has exited
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(confique::Config, Debug)]
+#[config(partial_attr(derive(Clone, Debug)))]
+#[config(partial_attr(serde(deny_unknown_fields, rename_all = "kebab-case")))]
+pub struct Config {
+    /// Enable module
+    #[config(default = false)]
+    pub enable: bool,
+
+    /// Command run when a device starts waiting for a touch, e.g. `["paplay", "bell.oga"]`;
+    /// commands get details in the `U2F_TD_EVENT`, `U2F_TD_SERIAL`, `U2F_TD_PRODUCT`,
+    /// `U2F_TD_MANUFACTURER`, `U2F_TD_VID`, `U2F_TD_PID`, `U2F_TD_PATH` and `U2F_TD_PENDING`
+    /// (number of devices waiting) environment variables
+    on_touch_needed: Option<Vec<String>>,
+
+    /// Command run when a device stops waiting for a touch, additionally gets `U2F_TD_OUTCOME`
+    /// (one of "touched", "timed-out", "denied", "cancelled" or "unknown") and `U2F_TD_DURATION`
+    /// (seconds the device was waiting)
+    on_touch_finished: Option<Vec<String>>,
+
+    /// Command run when a device is found, including devices present at startup
+    on_device_added: Option<Vec<String>>,
+
+    /// Command run when a device is removed
+    on_device_removed: Option<Vec<String>>,
+
+    /// How long commands may run before they are killed
+    #[config(default = "10s", deserialize_with = crate::config::duration)]
+    timeout: Duration,
+
+    /// How many commands may run at once, further commands wait for a running one to finish
+    #[config(default = 4)]
+    max_concurrent: usize,
+}
+
+/// A command run in the background with a time limit
+#[derive(Debug)]
+pub(crate) struct Job {
+    /// Output the command belongs to, counted as its failure
+    output: &'static str,
+    event: &'static str,
+    serial: Arc<str>,
+    command: Command,
+}
+
+#[culpa::try_fn]
+pub(crate) fn run(config: Config, mut rx: event::Receiver) -> Result<()> {
+    let (tx, jobs) = mpsc::channel::<Job>();
+    let jobs = Arc::new(Mutex::new(jobs));
+
+    for _ in 0..config.max_concurrent.max(1) {
+        std::thread::spawn({
+            let jobs = jobs.clone();
+            move || loop {
+                // release the lock before running the job so other workers can pick up jobs
+                let Ok(job) = jobs.lock().unwrap().recv() else {
+                    break;
+                };
+                job.run(config.timeout);
+            }
+        });
+    }
+
+    let mut pending = HashSet::new();
+
+    while let Some(event) = event::recv(&mut rx) {
+        let device = event.device();
+        match event.needed() {
+            Some(true) => pending.insert(device.id()),
+            Some(false) => pending.remove(&device.id()),
+            None => false,
+        };
+
+        let (name, command) = match &event {
+            Event::TouchNeeded { .. } => ("touch-needed", &config.on_touch_needed),
+            Event::TouchFinished { .. } => ("touch-finished", &config.on_touch_finished),
+            Event::DeviceAdded { .. } => ("device-added", &config.on_device_added),
+            Event::DeviceRemoved { .. } => ("device-removed", &config.on_device_removed),
+            Event::SnoozeRequested { .. } => continue,
+        };
+        let Some((program, args)) = command.as_deref().and_then(|c| c.split_first()) else {
+            continue;
+        };
+
+        let mut command = Command::new(program);
+        command
+            .args(args)
+            .env("U2F_TD_EVENT", name)
+            .env("U2F_TD_PENDING", pending.len().to_string());
+        if let Event::TouchFinished {
+            outcome, duration, ..
+        } = &event
+        {
+            command
+                .env("U2F_TD_OUTCOME", outcome.name())
+                .env("U2F_TD_DURATION", duration.as_secs().to_string());
+        }
+
+        if tx.send(Job::new("exec", name, device, command)).is_err() {
+            break;
+        }
+    }
+}
+
+/// Describe `device` to a command in `U2F_TD_*` environment variables
+fn device_env(command: &mut Command, device: &device::Info) {
+    command
+        .env("U2F_TD_SERIAL", &*device.serial)
+        .env("U2F_TD_PRODUCT", &device.product)
+        .env("U2F_TD_MANUFACTURER", &device.manufacturer)
+        .env("U2F_TD_VID", format!("{:04x}", device.vendor_id))
+        .env("U2F_TD_PID", format!("{:04x}", device.product_id))
+        .env("U2F_TD_PATH", device.path.as_str());
+}
+
+impl Job {
+    /// Prepare `command` to run for `event`, describing `device` in its environment
+    pub(crate) fn new(
+        output: &'static str,
+        event: &'static str,
+        device: &device::Info,
+        mut command: Command,
+    ) -> Self {
+        device_env(&mut command, device);
+        Self {
+            output,
+            event,
+            serial: device.serial.clone(),
+            command,
+        }
+    }
+
+    /// Run the command to completion, killing it if it takes longer than `timeout`, and always
+    /// waiting on it so it doesn't linger as a zombie
+    pub(crate) fn run(mut self, timeout: Duration) {
+        let span = info_span!("exec", event = self.event, device.serial = %self.serial);
+        let _guard = span.clone().entered();
+
+        let mut child = match self
+            .command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // so anything the command starts in turn can be killed along with it
+            .process_group(0)
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(err) => {
+                metrics::OUTPUT_FAILURES.increment(&[self.output]);
+                warn!("failed to run command: {err}");
+                return;
+            }
+        };
+
+        if let Some(stdout) = child.stdout.take() {
+            log_lines(span.clone(), stdout, |line| info!("stdout: {line}"));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            log_lines(span.clone(), stderr, |line| warn!("stderr: {line}"));
+        }
+
+        let deadline = Instant::now() + timeout;
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break Ok(status),
+                Ok(None) if Instant::now() < deadline => std::thread::sleep(POLL_INTERVAL),
+                Ok(None) => {
+                    warn!(?timeout, "command timed out, killing it");
+                    kill(&mut child);
+                    break child.wait();
+                }
+                Err(err) => {
+                    warn!("failed to check on command, killing it: {err}");
+                    kill(&mut child);
+                    break child.wait();
+                }
+            }
+        };
+
+        match status {
+            Ok(status) if status.success() => debug!("command finished"),
+            Ok(status) => {
+                metrics::OUTPUT_FAILURES.increment(&[self.output]);
+                warn!(%status, "command failed");
+            }
+            Err(err) => {
+                metrics::OUTPUT_FAILURES.increment(&[self.output]);
+                warn!("failed to wait for command: {err}");
+            }
+        }
+    }
+}
+
+/// Kill the command's whole process group, falling back to only the command itself
+fn kill(child: &mut Child) {
+    let Ok(pid) = libc::pid_t::try_from(child.id()) else {
+        let _ = child.kill();
+        return;
+    };
+    // SAFETY: kill has no memory safety requirements, and the group is our child's own
+    if unsafe { libc::kill(-pid, libc::SIGKILL) } != 0 {
+        let _ = child.kill();
+    }
+}
+
+/// Log each line of a command's output as it arrives
+fn log_lines(span: Span, output: impl Read + Send + 'static, log: fn(&str)) {
+    std::thread::spawn(move || {
+        let _guard = span.entered();
+        for line in BufReader::new(output).lines() {
+            match line {
+                Ok(line) => log(&line),
+                Err(err) => {
+                    debug!("stopped reading command output: {err}");
+                    break;
+                }
+            }
+        }
+    });
+}
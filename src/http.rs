@@ -0,0 +1,78 @@
+use eyre::{bail, ensure, OptionExt, Result};
+use std::io::{BufRead, Read, Write};
+
+// Requests we serve are tiny, anything bigger than this is not a client we care about
+const MAX_REQUEST_HEAD_SIZE: u64 = 8 * 1024;
+
+#[derive(Debug)]
+pub(crate) struct Request {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) host: Option<String>,
+}
+
+impl Request {
+    /// Read the request line and headers, any body and headers other than `Host` are ignored
+    #[culpa::try_fn]
+    pub(crate) fn read_from(stream: impl Read) -> Result<Self> {
+        let mut reader = std::io::BufReader::new(stream.take(MAX_REQUEST_HEAD_SIZE));
+        let mut line = String::new();
+
+        reader.read_line(&mut line)?;
+        let mut parts = line.split_ascii_whitespace();
+        let method = parts.next().ok_or_eyre("missing method")?.to_owned();
+        let target = parts.next().ok_or_eyre("missing request target")?;
+        let version = parts.next().ok_or_eyre("missing http version")?;
+        ensure!(
+            version.starts_with("HTTP/1."),
+            "unsupported http version {version}"
+        );
+        // we don't care about any query parameters
+        let path = target.split('?').next().unwrap_or_default().to_owned();
+
+        let mut host = None;
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                bail!("connection closed before end of headers");
+            }
+            if line.trim_end().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("host") {
+                    host = Some(value.trim().to_owned());
+                }
+            }
+        }
+
+        Self { method, path, host }
+    }
+}
+
+/// Write a response head, if `body` is `None` the connection is left open for streaming
+pub(crate) fn write_response(
+    mut stream: impl Write,
+    status: &str,
+    headers: &[(&str, &str)],
+    body: Option<&[u8]>,
+) -> std::io::Result<()> {
+    write!(stream, "HTTP/1.1 {status}\r\n")?;
+    for (name, value) in headers {
+        write!(stream, "{name}: {value}\r\n")?;
+    }
+    match body {
+        Some(body) => {
+            write!(
+                stream,
+                "Content-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )?;
+            stream.write_all(body)?;
+        }
+        None => {
+            write!(stream, "Cache-Control: no-cache\r\n\r\n")?;
+        }
+    }
+    stream.flush()
+}
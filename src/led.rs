@@ -0,0 +1,303 @@
+use camino::Utf8PathBuf;
+use eyre::{ensure, Result, WrapErr};
+use std::{
+    sync::mpsc::RecvTimeoutError,
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, warn};
+
+use crate::{event, metrics};
+
+#[derive(confique::Config, Debug)]
+#[config(partial_attr(derive(Clone, Debug)))]
+#[config(partial_attr(serde(deny_unknown_fields, rename_all = "kebab-case")))]
+pub struct Config {
+    /// Enable module
+    #[config(default = false)]
+    pub enable: bool,
+
+    /// Names of the LEDs to drive while a touch is pending, from `/sys/class/leds`, e.g.
+    /// `["input3::scrolllock"]`
+    #[config(default = [])]
+    leds: Vec<String>,
+
+    /// Kernel trigger to set while a touch is pending, e.g. "timer" or "heartbeat", by default
+    /// the brightness is toggled instead
+    trigger: Option<String>,
+
+    /// How long the LED is lit each blink, also used for the "timer" trigger
+    #[config(default = "250ms", deserialize_with = crate::config::duration)]
+    on: Duration,
+
+    /// How long the LED is dark between blinks, also used for the "timer" trigger
+    #[config(default = "250ms", deserialize_with = crate::config::duration)]
+    off: Duration,
+
+    /// Brightness to use while lit, by default the LED's `max_brightness`
+    brightness: Option<u32>,
+
+    /// Root of the sysfs mount, e.g. to test against a temporary directory
+    #[config(default = "/sys")]
+    sysfs_root: Utf8PathBuf,
+}
+
+#[derive(Debug)]
+struct Led {
+    dir: Utf8PathBuf,
+    /// Brightness to write while lit
+    on: String,
+    /// The state from before we took over the LED, only set while it is being driven
+    saved: Option<Saved>,
+}
+
+#[derive(Debug)]
+struct Saved {
+    trigger: String,
+    brightness: String,
+}
+
+impl Led {
+    #[culpa::try_fn]
+    fn new(dir: Utf8PathBuf, brightness: Option<u32>) -> Result<Self> {
+        let mut led = Self {
+            dir,
+            on: String::new(),
+            saved: None,
+        };
+        led.on = match brightness {
+            Some(brightness) => brightness.to_string(),
+            None => led.read("max_brightness")?,
+        };
+        led
+    }
+
+    #[culpa::try_fn]
+    fn read(&self, name: &str) -> Result<String> {
+        let path = self.dir.join(name);
+        let value =
+            std::fs::read_to_string(&path).wrap_err_with(|| format!("failed to read {path}"))?;
+        value.trim().to_owned()
+    }
+
+    #[culpa::try_fn]
+    fn write(&self, name: &str, value: &str) -> Result<()> {
+        let path = self.dir.join(name);
+        std::fs::write(&path, value).wrap_err_with(|| format!("failed to write {path}"))?;
+    }
+
+    /// The active trigger, shown in brackets in the list of available triggers
+    #[culpa::try_fn]
+    fn trigger(&self) -> Result<String> {
+        let triggers = self.read("trigger")?;
+        let active = triggers
+            .split_ascii_whitespace()
+            .find_map(|trigger| trigger.strip_prefix('[')?.strip_suffix(']'));
+        active.unwrap_or(&triggers).to_owned()
+    }
+
+    /// Save the current state and take over the LED
+    #[culpa::try_fn]
+    fn start(&mut self, config: &Config) -> Result<()> {
+        let saved = Saved {
+            trigger: self.trigger()?,
+            brightness: self.read("brightness")?,
+        };
+        debug!(dir = %self.dir, ?saved, "taking over led");
+        // before writing anything, so the LED is put back even if taking it over fails part way
+        self.saved = Some(saved);
+
+        match &config.trigger {
+            Some(trigger) => {
+                self.write("trigger", trigger)?;
+                if trigger == "timer" {
+                    self.write("delay_on", &config.on.as_millis().to_string())?;
+                    self.write("delay_off", &config.off.as_millis().to_string())?;
+                }
+            }
+            None => {
+                // stop any existing trigger, e.g. the keyboard state, from fighting our blinking
+                self.write("trigger", "none")?;
+            }
+        }
+    }
+
+    #[culpa::try_fn]
+    fn set(&self, lit: bool) -> Result<()> {
+        self.write("brightness", if lit { &self.on } else { "0" })?;
+    }
+
+    /// Put the LED back how it was before `start`
+    #[culpa::try_fn]
+    fn restore(&mut self) -> Result<()> {
+        let Some(saved) = self.saved.take() else {
+            return;
+        };
+        debug!(dir = %self.dir, ?saved, "restoring led");
+        self.write("trigger", &saved.trigger)?;
+        // any other trigger sets the brightness itself, and writing it would disable the trigger
+        if saved.trigger == "none" {
+            self.write("brightness", &saved.brightness)?;
+        }
+    }
+}
+
+#[culpa::try_fn]
+pub(crate) fn run(config: Config, rx: event::Receiver) -> Result<()> {
+    ensure!(!config.leds.is_empty(), "led output has no leds configured");
+
+    let root = config.sysfs_root.join("class/leds");
+    let mut leds = Vec::new();
+    for name in &config.leds {
+        let dir = root.join(name);
+        ensure!(dir.is_dir(), "led {name} not found in {root}");
+        leds.push(Led::new(dir, config.brightness)?);
+    }
+    info!(leds = ?config.leds, "driving leds");
+
+    let changes = event::relay_pending(rx);
+
+    let mut lit = false;
+    let mut next_toggle: Option<Instant> = None;
+    loop {
+        let change = match next_toggle {
+            Some(at) => changes.recv_timeout(at.saturating_duration_since(Instant::now())),
+            None => changes.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        match change {
+            Ok(true) => {
+                debug!("touch pending, driving leds");
+                for led in &mut leds {
+                    if let Err(err) = led.start(&config) {
+                        fail(&err);
+                        if let Err(err) = led.restore() {
+                            fail(&err);
+                        }
+                    }
+                }
+                if config.trigger.is_none() {
+                    lit = false;
+                    next_toggle = Some(Instant::now());
+                }
+            }
+            Ok(false) => {
+                debug!("no touch pending, restoring leds");
+                restore(&mut leds);
+                next_toggle = None;
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                lit = !lit;
+                for led in leds.iter_mut().filter(|led| led.saved.is_some()) {
+                    if let Err(err) = led.set(lit) {
+                        fail(&err);
+                        // stop driving it rather than failing every blink
+                        led.saved = None;
+                    }
+                }
+                let duration = if lit { config.on } else { config.off };
+                next_toggle = Some(Instant::now() + duration);
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                restore(&mut leds);
+                break;
+            }
+        }
+    }
+}
+
+fn restore(leds: &mut [Led]) {
+    for led in leds {
+        if let Err(err) = led.restore() {
+            fail(&err);
+        }
+    }
+}
+
+fn fail(err: &eyre::Report) {
+    metrics::OUTPUT_FAILURES.increment(&["led"]);
+    warn!("failed to drive led: {err:?}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::testing::{self, eventually, touch_finished, touch_needed};
+    use camino::Utf8Path;
+    use std::collections::BTreeSet;
+
+    fn read(dir: &Utf8Path, name: &str) -> String {
+        std::fs::read_to_string(dir.join(name)).unwrap()
+    }
+
+    /// Config driving fake LEDs under `root`, each given as (name, trigger, brightness)
+    fn fake_leds(root: &Utf8Path, leds: &[(&str, &str, &str)]) -> Config {
+        for (name, trigger, brightness) in leds {
+            let dir = root.join("class/leds").join(name);
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("trigger"), trigger).unwrap();
+            std::fs::write(dir.join("brightness"), brightness).unwrap();
+            std::fs::write(dir.join("max_brightness"), "255\n").unwrap();
+        }
+        Config {
+            enable: true,
+            leds: leds.iter().map(|(name, ..)| (*name).to_owned()).collect(),
+            trigger: None,
+            on: Duration::from_millis(10),
+            off: Duration::from_millis(10),
+            brightness: None,
+            sysfs_root: root.to_owned(),
+        }
+    }
+
+    #[test]
+    fn blinks_and_restores() {
+        let root = tempfile::tempdir().unwrap();
+        let root = Utf8Path::from_path(root.path()).unwrap();
+        let leds = root.join("class/leds");
+        let config = fake_leds(
+            root,
+            &[
+                ("plain", "[none] timer heartbeat", "1"),
+                ("keyboard", "none timer [kbd-scrolllock]", "0"),
+            ],
+        );
+        let (events, _output) = testing::spawn(move |rx| run(config, rx));
+        let device = testing::device();
+        events.send(touch_needed(&device)).unwrap();
+
+        let (plain, keyboard) = (leds.join("plain"), leds.join("keyboard"));
+        eventually(|| read(&keyboard, "trigger") == "none");
+        assert_eq!(read(&plain, "trigger"), "none");
+        let mut seen = BTreeSet::new();
+        eventually(|| {
+            seen.insert(read(&plain, "brightness"));
+            seen.contains("0") && seen.contains("255")
+        });
+
+        events.send(touch_finished(&device)).unwrap();
+        eventually(|| read(&keyboard, "trigger") == "kbd-scrolllock");
+        eventually(|| read(&plain, "brightness") == "1");
+        assert_eq!(read(&plain, "trigger"), "none");
+
+        // no longer blinking once restored
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(read(&plain, "brightness"), "1");
+    }
+
+    #[test]
+    fn restores_when_stopped() {
+        let root = tempfile::tempdir().unwrap();
+        let root = Utf8Path::from_path(root.path()).unwrap();
+        let keyboard = root.join("class/leds/keyboard");
+        let config = fake_leds(root, &[("keyboard", "none [kbd-scrolllock]", "0")]);
+        let (events, output) = testing::spawn(move |rx| run(config, rx));
+        events.send(touch_needed(&testing::device())).unwrap();
+        eventually(|| read(&keyboard, "trigger") == "none");
+
+        // the pending relay disconnects on shutdown as it does once there are no more events, so
+        // the output puts the led back and stops while the touch is still pending
+        drop(events);
+        output.join().unwrap().unwrap();
+        assert_eq!(read(&keyboard, "trigger"), "kbd-scrolllock");
+    }
+}
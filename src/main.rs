@@ -1,5 +1,5 @@
 use clap::Parser;
-use eyre::Result;
+use eyre::{ensure, Result};
 use std::{
     collections::{hash_map::Entry, HashMap},
     time::Duration,
@@ -7,15 +7,36 @@ use std::{
 use tracing::{debug, info, info_span, warn};
 use tracing_subscriber::{filter::LevelFilter, layer::SubscriberExt, EnvFilter};
 
+mod atomic_file;
+mod bar;
 mod command;
 mod config;
+mod dbus;
 mod device;
+mod event;
+mod exec;
+mod http;
+mod led;
 mod message;
+mod metrics;
+mod models;
+mod mqtt;
 mod notify;
+mod openrgb;
 mod packet;
+mod portal;
+mod prometheus;
+mod push;
+mod shutdown;
 mod socket;
+mod state_file;
+mod stdout;
+mod template;
+mod timeouts;
+mod tray;
+mod web;
 
-use crate::{config::Config, device::Device};
+use crate::{config::Config, device::Device, event::Event};
 
 const NEW_DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(5);
 
@@ -27,6 +48,15 @@ pub(crate) struct App {
     #[arg(long)]
     socket: bool,
 
+    /// (Optional) Write events to stdout, starting with the devices found at startup, e.g. to pipe
+    /// into `jq`.
+    #[arg(long)]
+    stdout: bool,
+
+    /// Format of the events written to stdout.
+    #[arg(long, value_enum, default_value = "json", requires = "stdout")]
+    format: stdout::Format,
+
     /// Config overrides to apply, these should be fragments of the config file.
     #[arg(long = "config-toml", value_name = "TOML", value_parser = toml::from_str::<config::Partial>)]
     config_fragments: Vec<config::Partial>,
@@ -50,28 +80,98 @@ fn main() -> Result<()> {
             .with(tracing_error::ErrorLayer::default()),
     )?;
 
+    // before starting any threads, so they all leave the signals to the handler
+    shutdown::handle_signals()?;
+
     let app = App::parse();
     let config = Config::load(app.config_fragments)?;
     tracing::trace!(?config, "loaded config");
+    // read before the notify config is moved to its output
+    let snooze_supported = config.notify.enable;
+    models::set_enabled(config.notify.builtin_models);
 
-    let (tx, _) = tokio::sync::broadcast::channel(1);
+    // room for a burst of events, e.g. every device being added at startup
+    let (tx, _) = tokio::sync::broadcast::channel(16);
 
     if app.socket {
-        info!("starting socket output");
-        std::thread::spawn({
-            let rx = tx.subscribe();
-            move || socket::run(rx)
-        });
+        let rx = tx.subscribe();
+        spawn_output("socket", move || socket::run(rx));
+    }
+
+    ensure!(
+        !(app.stdout && config.bar.enable),
+        "the stdout and bar outputs cannot both write to stdout"
+    );
+
+    if app.stdout {
+        let rx = tx.subscribe();
+        spawn_output("stdout", move || stdout::run(app.format, rx));
     }
 
     if config.notify.enable {
-        info!("starting notify output");
-        std::thread::spawn({
-            let rx = tx.subscribe();
-            move || notify::run(config.notify, rx)
+        let rx = tx.subscribe();
+        spawn_output("notify", move || notify::run(config.notify, rx));
+    }
+
+    if config.exec.enable {
+        let rx = tx.subscribe();
+        spawn_output("exec", move || exec::run(config.exec, rx));
+    }
+
+    if config.dbus.enable {
+        let (rx, tx) = (tx.subscribe(), tx.clone());
+        spawn_output("dbus", move || {
+            dbus::run(config.dbus, rx, tx, snooze_supported)
+        });
+    }
+
+    if config.tray.enable {
+        let (rx, tx) = (tx.subscribe(), tx.clone());
+        spawn_output("tray", move || {
+            tray::run(config.tray, rx, tx, snooze_supported)
         });
     }
 
+    if config.bar.enable {
+        let rx = tx.subscribe();
+        spawn_output("bar", move || bar::run(config.bar, rx));
+    }
+
+    if config.web.enable {
+        let rx = tx.subscribe();
+        spawn_output("web", move || web::run(config.web, rx));
+    }
+
+    if config.led.enable {
+        let rx = tx.subscribe();
+        spawn_output("led", move || led::run(config.led, rx));
+    }
+
+    if config.mqtt.enable {
+        let rx = tx.subscribe();
+        spawn_output("mqtt", move || mqtt::run(config.mqtt, rx));
+    }
+
+    if config.openrgb.enable {
+        let rx = tx.subscribe();
+        spawn_output("openrgb", move || openrgb::run(config.openrgb, rx));
+    }
+
+    if config.push.enable {
+        let rx = tx.subscribe();
+        spawn_output("push", move || push::run(config.push, rx));
+    }
+
+    if config.state_file.enable {
+        let rx = tx.subscribe();
+        spawn_output("state-file", move || state_file::run(config.state_file, rx));
+    }
+
+    if config.prometheus.enable {
+        let rx = tx.subscribe();
+        spawn_output("prometheus", move || prometheus::run(config.prometheus, rx));
+    }
+
     let mut hidapi = hidapi::HidApi::new_without_enumerate()?;
     let mut threads = HashMap::new();
 
@@ -83,18 +183,24 @@ fn main() -> Result<()> {
         for device in Device::find(&hidapi) {
             match device {
                 Ok(device) => {
-                    let _guard = info_span!("device", %device.serial).entered();
+                    let _guard = info_span!("device", %device.info.serial).entered();
 
                     match threads.entry(device.path().to_owned()) {
                         Entry::Vacant(entry) => {
                             info!("adding new device");
+                            let _ = tx.send(Event::DeviceAdded {
+                                device: device.info.clone(),
+                            });
                             entry.insert(std::thread::spawn({
                                 let tx = tx.clone();
                                 move || {
-                                    let _guard = info_span!("device", %device.serial).entered();
-                                    if let Err(err) = device.process_messages(tx) {
+                                    let _guard =
+                                        info_span!("device", %device.info.serial).entered();
+                                    let info = device.info.clone();
+                                    if let Err(err) = device.process_messages(tx.clone()) {
                                         info!("device thread died (probably removed): {err:?}");
                                     }
+                                    let _ = tx.send(Event::DeviceRemoved { device: info });
                                 }
                             }));
                         }
@@ -112,3 +218,14 @@ fn main() -> Result<()> {
         threads.retain(|_, thread| !thread.is_finished());
     }
 }
+
+/// Run an output on its own thread, counting it stopping with an error as an output failure
+fn spawn_output(name: &'static str, run: impl FnOnce() -> Result<()> + Send + 'static) {
+    info!("starting {name} output");
+    std::thread::spawn(move || {
+        if let Err(err) = run() {
+            metrics::OUTPUT_FAILURES.increment(&[name]);
+            warn!("{name} output failed: {err:?}");
+        }
+    });
+}
@@ -5,6 +5,7 @@ use tracing::{trace, trace_span};
 pub(crate) use crate::packet::Channel;
 use crate::{
     command::{self, Command},
+    metrics,
     packet::{Init, Packet, FIDO_CTAPHID_MAX_RECORD_SIZE},
 };
 
@@ -45,6 +46,7 @@ impl<'a> Message<'a> {
 
             let Packet::Init(init) = packet else {
                 trace!("skipping continuation while looking for new message");
+                metrics::RESYNCS.increment();
                 continue;
             };
 
@@ -0,0 +1,347 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// Prepended to every metric name when exported
+const PREFIX: &str = "u2f_td_";
+
+/// Upper bounds in seconds of the time to touch histogram buckets
+const TOUCH_DURATION_BUCKETS: &[f64] = &[0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0];
+
+/// A process-wide monotonically increasing count
+#[derive(Debug)]
+pub(crate) struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub(crate) fn increment(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A process-wide count of things currently existing
+#[derive(Debug)]
+pub(crate) struct Gauge(AtomicI64);
+
+impl Gauge {
+    const fn new() -> Self {
+        Self(AtomicI64::new(0))
+    }
+
+    pub(crate) fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn decrement(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A set of counters distinguished by the values of `labels`
+#[derive(Debug)]
+pub(crate) struct LabeledCounter {
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl LabeledCounter {
+    const fn new(labels: &'static [&'static str]) -> Self {
+        Self {
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// `values` are given in the same order as the labels
+    pub(crate) fn increment(&self, values: &[&str]) {
+        debug_assert_eq!(values.len(), self.labels.len());
+        let key = values.iter().map(|&value| value.to_owned()).collect();
+        *self.values.lock().unwrap().entry(key).or_default() += 1;
+    }
+}
+
+/// A distribution of observed values, distinguished by the values of `labels`
+#[derive(Debug)]
+pub(crate) struct Histogram {
+    labels: &'static [&'static str],
+    buckets: &'static [f64],
+    values: Mutex<BTreeMap<Vec<String>, Observations>>,
+}
+
+#[derive(Debug, Default)]
+struct Observations {
+    /// Non-cumulative count per bucket, with a final entry for values above every bucket
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    const fn new(labels: &'static [&'static str], buckets: &'static [f64]) -> Self {
+        Self {
+            labels,
+            buckets,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// `values` are given in the same order as the labels
+    pub(crate) fn observe(&self, values: &[&str], value: f64) {
+        debug_assert_eq!(values.len(), self.labels.len());
+        let key = values.iter().map(|&value| value.to_owned()).collect();
+        let mut all = self.values.lock().unwrap();
+        let observations = all.entry(key).or_insert_with(|| Observations {
+            counts: vec![0; self.buckets.len() + 1],
+            sum: 0.0,
+        });
+        let bucket = self
+            .buckets
+            .iter()
+            .position(|&bound| value <= bound)
+            .unwrap_or(self.buckets.len());
+        observations.counts[bucket] += 1;
+        observations.sum += value;
+    }
+}
+
+trait Metric: Sync {
+    fn kind(&self) -> &'static str;
+
+    /// Write the samples in the Prometheus text format
+    fn render(&self, name: &str, out: &mut String);
+}
+
+impl Metric for Counter {
+    fn kind(&self) -> &'static str {
+        "counter"
+    }
+
+    fn render(&self, name: &str, out: &mut String) {
+        let _ = writeln!(out, "{name} {}", self.get());
+    }
+}
+
+impl Metric for Gauge {
+    fn kind(&self) -> &'static str {
+        "gauge"
+    }
+
+    fn render(&self, name: &str, out: &mut String) {
+        let _ = writeln!(out, "{name} {}", self.get());
+    }
+}
+
+impl Metric for LabeledCounter {
+    fn kind(&self) -> &'static str {
+        "counter"
+    }
+
+    fn render(&self, name: &str, out: &mut String) {
+        for (values, count) in &*self.values.lock().unwrap() {
+            let labels = labels(self.labels, values, None);
+            let _ = writeln!(out, "{name}{{{labels}}} {count}");
+        }
+    }
+}
+
+impl Metric for Histogram {
+    fn kind(&self) -> &'static str {
+        "histogram"
+    }
+
+    fn render(&self, name: &str, out: &mut String) {
+        for (values, observations) in &*self.values.lock().unwrap() {
+            let mut cumulative = 0;
+            let bounds = self.buckets.iter().map(|bound| bound.to_string());
+            for (bound, count) in bounds.chain(["+Inf".to_owned()]).zip(&observations.counts) {
+                cumulative += count;
+                let labels = labels(self.labels, values, Some(&bound));
+                let _ = writeln!(out, "{name}_bucket{{{labels}}} {cumulative}");
+            }
+            let labels = labels(self.labels, values, None);
+            let _ = writeln!(out, "{name}_sum{{{labels}}} {}", observations.sum);
+            let _ = writeln!(out, "{name}_count{{{labels}}} {cumulative}");
+        }
+    }
+}
+
+/// Format label pairs, escaping the values, optionally with the histogram bucket bound
+fn labels(names: &[&str], values: &[String], le: Option<&str>) -> String {
+    let mut out = String::new();
+    let pairs = names
+        .iter()
+        .copied()
+        .zip(values.iter().map(|value| &**value));
+    for (name, value) in pairs.chain(le.map(|le| ("le", le))) {
+        if !out.is_empty() {
+            out.push(',');
+        }
+        let value = value
+            .replace('\\', r"\\")
+            .replace('"', r#"\""#)
+            .replace('\n', r"\n");
+        let _ = write!(out, "{name}=\"{value}\"");
+    }
+    out
+}
+
+/// Notifications not shown because the request finished within the configured delay
+pub(crate) static NOTIFICATIONS_SUPPRESSED: Counter = Counter::new();
+
+/// Per device, how often it started waiting for a touch
+pub(crate) static TOUCHES_REQUESTED: LabeledCounter = LabeledCounter::new(&["device"]);
+
+/// Per device and outcome, how often it stopped waiting for a touch
+pub(crate) static TOUCHES_FINISHED: LabeledCounter = LabeledCounter::new(&["device", "outcome"]);
+
+/// Per device, how long it took to be touched
+pub(crate) static TOUCH_DURATION: Histogram = Histogram::new(&["device"], TOUCH_DURATION_BUCKETS);
+
+/// Per device, messages that could not be parsed
+pub(crate) static PROTOCOL_ERRORS: LabeledCounter = LabeledCounter::new(&["device"]);
+
+/// Continuation packets skipped while looking for the start of a message
+pub(crate) static RESYNCS: Counter = Counter::new();
+
+pub(crate) static DEVICES_ADDED: Counter = Counter::new();
+
+pub(crate) static DEVICES_REMOVED: Counter = Counter::new();
+
+/// Clients connected to the yubikey-touch-detector compatible socket
+pub(crate) static SOCKET_CLIENTS: Gauge = Gauge::new();
+
+/// Per output, failures to deliver an event, e.g. a command failing or a write erroring
+pub(crate) static OUTPUT_FAILURES: LabeledCounter = LabeledCounter::new(&["output"]);
+
+static METRICS: &[(&str, &str, &dyn Metric)] = &[
+    (
+        "notifications_suppressed_total",
+        "Notifications not shown because the touch was quicker than the delay",
+        &NOTIFICATIONS_SUPPRESSED,
+    ),
+    (
+        "touches_requested_total",
+        "Times a device started waiting for a touch",
+        &TOUCHES_REQUESTED,
+    ),
+    (
+        "touches_finished_total",
+        "Times a device stopped waiting for a touch, by outcome",
+        &TOUCHES_FINISHED,
+    ),
+    (
+        "touch_duration_seconds",
+        "How long devices waited before being touched",
+        &TOUCH_DURATION,
+    ),
+    (
+        "protocol_errors_total",
+        "Messages from a device that could not be parsed",
+        &PROTOCOL_ERRORS,
+    ),
+    (
+        "resyncs_total",
+        "Continuation packets skipped while looking for the start of a message",
+        &RESYNCS,
+    ),
+    (
+        "devices_added_total",
+        "Devices found, including those present at startup",
+        &DEVICES_ADDED,
+    ),
+    (
+        "devices_removed_total",
+        "Devices unplugged or stopped responding",
+        &DEVICES_REMOVED,
+    ),
+    (
+        "socket_clients",
+        "Clients currently connected to the socket output",
+        &SOCKET_CLIENTS,
+    ),
+    (
+        "output_failures_total",
+        "Failures to deliver events, by output",
+        &OUTPUT_FAILURES,
+    ),
+];
+
+/// Render every metric in the Prometheus text exposition format
+pub(crate) fn render() -> String {
+    render_all(METRICS)
+}
+
+fn render_all(metrics: &[(&str, &str, &dyn Metric)]) -> String {
+    let mut out = String::new();
+    for (name, help, metric) in metrics {
+        let name = format!("{PREFIX}{name}");
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {}", metric.kind());
+        metric.render(&name, &mut out);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_exposition_format() {
+        let started = Counter::new();
+        started.increment();
+        let clients = Gauge::new();
+        clients.increment();
+        clients.increment();
+        clients.decrement();
+        let failures = LabeledCounter::new(&["output", "reason"]);
+        failures.increment(&["exec", "quote \" back\\slash\nnewline"]);
+        failures.increment(&["exec", "quote \" back\\slash\nnewline"]);
+        failures.increment(&["bar", "plain"]);
+        let durations = Histogram::new(&["device"], &[0.5, 2.0]);
+        for value in [0.25, 0.5, 1.5, 3.0] {
+            durations.observe(&["12345678"], value);
+        }
+
+        let rendered = render_all(&[
+            ("started_total", "Times started", &started),
+            ("clients", "Connected clients", &clients),
+            ("failures_total", "Failures", &failures),
+            ("duration_seconds", "Durations", &durations),
+        ]);
+        let expected = r#"# HELP u2f_td_started_total Times started
+# TYPE u2f_td_started_total counter
+u2f_td_started_total 1
+# HELP u2f_td_clients Connected clients
+# TYPE u2f_td_clients gauge
+u2f_td_clients 1
+# HELP u2f_td_failures_total Failures
+# TYPE u2f_td_failures_total counter
+u2f_td_failures_total{output="bar",reason="plain"} 1
+u2f_td_failures_total{output="exec",reason="quote \" back\\slash\nnewline"} 2
+# HELP u2f_td_duration_seconds Durations
+# TYPE u2f_td_duration_seconds histogram
+u2f_td_duration_seconds_bucket{device="12345678",le="0.5"} 2
+u2f_td_duration_seconds_bucket{device="12345678",le="2"} 3
+u2f_td_duration_seconds_bucket{device="12345678",le="+Inf"} 4
+u2f_td_duration_seconds_sum{device="12345678"} 5.25
+u2f_td_duration_seconds_count{device="12345678"} 4
+"#;
+        assert_eq!(rendered, expected);
+    }
+}
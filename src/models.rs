@@ -0,0 +1,119 @@
+use camino::Utf8PathBuf;
+use directories::ProjectDirs;
+use eyre::{OptionExt, Result};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    OnceLock,
+};
+use tracing::warn;
+
+use crate::device;
+
+/// Whether devices are recognized at all, turned off by `notify.builtin-models`
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// A bundled image, written out to the cache directory on first use since notification servers
+/// need a path to load it from
+#[derive(Debug)]
+pub(crate) struct Icon {
+    name: &'static str,
+    svg: &'static str,
+    path: OnceLock<Option<Utf8PathBuf>>,
+}
+
+impl Icon {
+    const fn new(name: &'static str, svg: &'static str) -> Self {
+        Self {
+            name,
+            svg,
+            path: OnceLock::new(),
+        }
+    }
+
+    pub(crate) fn path(&self) -> Option<&Utf8PathBuf> {
+        self.path
+            .get_or_init(|| {
+                self.write()
+                    .inspect_err(|err| warn!(icon = self.name, "cannot write icon: {err:?}"))
+                    .ok()
+            })
+            .as_ref()
+    }
+
+    #[culpa::try_fn]
+    fn write(&self) -> Result<Utf8PathBuf> {
+        let dirs = ProjectDirs::from("", "", "u2f-touch-detector")
+            .ok_or_eyre("cannot get cache directory")?;
+        let dir = Utf8PathBuf::try_from(dirs.cache_dir().join("icons"))?;
+        let path = dir.join(format!("{}.svg", self.name));
+        if std::fs::read_to_string(&path).ok().as_deref() != Some(self.svg) {
+            std::fs::create_dir_all(&dir)?;
+            std::fs::write(&path, self.svg)?;
+        }
+        path
+    }
+}
+
+static YUBIKEY: Icon = Icon::new("yubikey", include_str!("../icons/yubikey.svg"));
+static SOLOKEY: Icon = Icon::new("solokey", include_str!("../icons/solokey.svg"));
+static NITROKEY: Icon = Icon::new("nitrokey", include_str!("../icons/nitrokey.svg"));
+static TITAN: Icon = Icon::new("titan", include_str!("../icons/titan.svg"));
+static FEITIAN: Icon = Icon::new("feitian", include_str!("../icons/feitian.svg"));
+static GENERIC: Icon = Icon::new("generic", include_str!("../icons/generic.svg"));
+
+#[derive(Debug)]
+pub(crate) struct Model {
+    pub(crate) name: &'static str,
+    pub(crate) icon: &'static Icon,
+}
+
+/// Known devices by USB vendor and product id, a `None` product id matches any product from that
+/// vendor
+#[rustfmt::skip]
+static MODELS: &[(u16, Option<u16>, Model)] = &[
+    (0x1050, Some(0x0113), Model { name: "YubiKey NEO (FIDO)", icon: &YUBIKEY }),
+    (0x1050, Some(0x0114), Model { name: "YubiKey NEO (OTP+FIDO)", icon: &YUBIKEY }),
+    (0x1050, Some(0x0115), Model { name: "YubiKey NEO (FIDO+CCID)", icon: &YUBIKEY }),
+    (0x1050, Some(0x0116), Model { name: "YubiKey NEO (OTP+FIDO+CCID)", icon: &YUBIKEY }),
+    (0x1050, Some(0x0120), Model { name: "Security Key by Yubico", icon: &YUBIKEY }),
+    (0x1050, Some(0x0200), Model { name: "YubiKey U2F", icon: &YUBIKEY }),
+    (0x1050, Some(0x0402), Model { name: "YubiKey 5 (FIDO)", icon: &YUBIKEY }),
+    (0x1050, Some(0x0403), Model { name: "YubiKey 5 (OTP+FIDO)", icon: &YUBIKEY }),
+    (0x1050, Some(0x0406), Model { name: "YubiKey 5 (FIDO+CCID)", icon: &YUBIKEY }),
+    (0x1050, Some(0x0407), Model { name: "YubiKey 5 (OTP+FIDO+CCID)", icon: &YUBIKEY }),
+    (0x1050, None, Model { name: "YubiKey", icon: &YUBIKEY }),
+    (0x0483, Some(0xa2ca), Model { name: "Solo", icon: &SOLOKEY }),
+    (0x1209, Some(0xbeee), Model { name: "Solo 2", icon: &SOLOKEY }),
+    (0x20a0, Some(0x4287), Model { name: "Nitrokey FIDO U2F", icon: &NITROKEY }),
+    (0x20a0, Some(0x42b1), Model { name: "Nitrokey FIDO2", icon: &NITROKEY }),
+    (0x20a0, Some(0x42b2), Model { name: "Nitrokey 3", icon: &NITROKEY }),
+    (0x18d1, Some(0x5026), Model { name: "Google Titan Security Key", icon: &TITAN }),
+    (0x096e, Some(0x0858), Model { name: "Google Titan Security Key", icon: &TITAN }),
+    (0x096e, Some(0x085b), Model { name: "Feitian BioPass FIDO2", icon: &FEITIAN }),
+    (0x096e, None, Model { name: "Feitian ePass FIDO", icon: &FEITIAN }),
+    (0x1d50, Some(0x60fc), Model { name: "OnlyKey", icon: &GENERIC }),
+    (0x1ea8, Some(0xf025), Model { name: "Thetis FIDO U2F", icon: &GENERIC }),
+    (0x2ccf, Some(0x0880), Model { name: "HyperFIDO", icon: &GENERIC }),
+    (0x534c, Some(0x0001), Model { name: "Trezor One", icon: &GENERIC }),
+    (0x1209, Some(0x53c1), Model { name: "Trezor Model T", icon: &GENERIC }),
+];
+
+/// Turn recognizing devices on or off for every output
+pub(crate) fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// The built-in model `info` is recognized as, unless that is turned off
+pub(crate) fn find(info: &device::Info) -> Option<&'static Model> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    lookup(info.vendor_id, info.product_id)
+}
+
+fn lookup(vendor_id: u16, product_id: u16) -> Option<&'static Model> {
+    MODELS
+        .iter()
+        .find(|(vid, pid, _)| *vid == vendor_id && pid.map_or(true, |pid| pid == product_id))
+        .map(|(_, _, model)| model)
+}
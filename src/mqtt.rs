@@ -0,0 +1,527 @@
+use eyre::Result;
+use rumqttc::{Client, LastWill, MqttOptions, QoS};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    device,
+    event::{self, Event, Outcome},
+    metrics, models,
+};
+
+/// How long to wait before reconnecting after losing the connection to the broker
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(confique::Config, Debug)]
+#[config(partial_attr(derive(Clone, Debug)))]
+#[config(partial_attr(serde(deny_unknown_fields, rename_all = "kebab-case")))]
+pub struct Config {
+    /// Enable module
+    #[config(default = false)]
+    pub enable: bool,
+
+    /// Hostname of the broker
+    #[config(default = "localhost")]
+    host: String,
+
+    /// Port of the broker, TLS is not supported
+    #[config(default = 1883)]
+    port: u16,
+
+    /// Username to authenticate to the broker with
+    username: Option<String>,
+
+    /// Password to authenticate to the broker with, requires `username`
+    password: Option<String>,
+
+    /// Client id to connect with, must be unique per broker
+    #[config(default = "u2f-touch-detector")]
+    client_id: String,
+
+    /// Prefix of all published topics: `<prefix>/status` is "online" or "offline",
+    /// `<prefix>/state` has the ids of devices waiting, and `<prefix>/devices/<id>` has the state
+    /// of each device, all retained with JSON payloads; the id is the serial, or for devices
+    /// without one the vendor id, product id and hidraw device, e.g. `1050_0407_hidraw3`
+    #[config(default = "u2f-touch-detector")]
+    topic_prefix: String,
+
+    /// Publish Home Assistant discovery messages so devices appear automatically
+    #[config(default = true)]
+    discovery: bool,
+
+    /// Topic prefix Home Assistant watches for discovery messages
+    #[config(default = "homeassistant")]
+    discovery_prefix: String,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Every device seen by id, removed devices are kept so they are republished as absent
+    devices: BTreeMap<String, Device>,
+}
+
+#[derive(Debug)]
+struct Device {
+    /// Identifies the device in topics and discovery, see `device::Info::id`
+    id: String,
+    info: Arc<device::Info>,
+    present: bool,
+    touch_needed: bool,
+    last_outcome: Option<Outcome>,
+}
+
+#[derive(Debug, Serialize)]
+struct Aggregate<'a> {
+    touch_needed: bool,
+    pending: Vec<&'a str>,
+}
+
+#[derive(Debug, Serialize)]
+struct DeviceState<'a> {
+    present: bool,
+    touch_needed: bool,
+    last_outcome: Option<Outcome>,
+    #[serde(flatten)]
+    info: &'a device::Info,
+}
+
+/// Home Assistant binary sensor discovery message
+#[derive(Debug, Serialize)]
+struct Discovery<'a> {
+    name: &'a str,
+    unique_id: String,
+    object_id: String,
+    state_topic: String,
+    value_template: &'a str,
+    availability_topic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<&'a str>,
+    device: DiscoveryDevice<'a>,
+}
+
+#[derive(Debug, Serialize)]
+struct DiscoveryDevice<'a> {
+    identifiers: [String; 1],
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manufacturer: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    serial_number: Option<&'a str>,
+}
+
+struct Publisher {
+    config: Config,
+    client: Client,
+}
+
+#[culpa::try_fn]
+pub(crate) fn run(config: Config, mut rx: event::Receiver) -> Result<()> {
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options
+        .set_keep_alive(Duration::from_secs(30))
+        .set_last_will(LastWill::new(
+            topic(&config, "status"),
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.as_deref().unwrap_or_default());
+    }
+
+    let (client, mut connection) = Client::new(options, 64);
+    let publisher = Arc::new(Publisher { config, client });
+    let state = Arc::new(Mutex::new(State::default()));
+
+    std::thread::spawn({
+        let publisher = publisher.clone();
+        let state = state.clone();
+        move || {
+            let mut connected = false;
+            for notification in connection.iter() {
+                match notification {
+                    Ok(rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_))) => {
+                        info!(host = %publisher.config.host, "connected to mqtt broker");
+                        connected = true;
+                        // the broker may have restarted and lost everything, or seen our will
+                        publisher.publish_all(&state.lock().unwrap());
+                    }
+                    Ok(notification) => debug!(?notification, "mqtt"),
+                    Err(err) => {
+                        if connected {
+                            metrics::OUTPUT_FAILURES.increment(&["mqtt"]);
+                            warn!("lost connection to mqtt broker: {err}");
+                        } else {
+                            debug!("cannot connect to mqtt broker: {err}");
+                        }
+                        connected = false;
+                        std::thread::sleep(RECONNECT_DELAY);
+                    }
+                }
+            }
+        }
+    });
+
+    while let Some(event) = event::recv(&mut rx) {
+        let info = event.device();
+        let mut state = state.lock().unwrap();
+        let id = sanitize(&info.id());
+        let device = state.devices.entry(id.clone()).or_insert_with(|| Device {
+            id,
+            info: info.clone(),
+            present: true,
+            touch_needed: false,
+            last_outcome: None,
+        });
+        let mut discover = false;
+        match &event {
+            Event::TouchNeeded { .. } => device.touch_needed = true,
+            Event::TouchFinished { outcome, .. } => {
+                device.touch_needed = false;
+                device.last_outcome = Some(*outcome);
+            }
+            Event::DeviceAdded { .. } => {
+                // the device may have been plugged into a different port
+                device.info = info.clone();
+                device.present = true;
+                discover = true;
+            }
+            Event::DeviceRemoved { .. } => {
+                device.present = false;
+                device.touch_needed = false;
+            }
+            Event::SnoozeRequested { .. } => continue,
+        }
+
+        if discover {
+            publisher.publish_discovery(device);
+        }
+        publisher.publish_device(device);
+        publisher.publish_aggregate(&state);
+    }
+}
+
+impl Publisher {
+    fn publish_all(&self, state: &State) {
+        self.publish(topic(&self.config, "status"), "online".into());
+        self.publish_aggregate_discovery();
+        for device in state.devices.values() {
+            self.publish_discovery(device);
+            self.publish_device(device);
+        }
+        self.publish_aggregate(state);
+    }
+
+    fn publish_aggregate(&self, state: &State) {
+        let pending: Vec<_> = state
+            .devices
+            .values()
+            .filter(|device| device.touch_needed)
+            .map(|device| &*device.id)
+            .collect();
+        let aggregate = Aggregate {
+            touch_needed: !pending.is_empty(),
+            pending,
+        };
+        self.publish_json(topic(&self.config, "state"), &aggregate);
+    }
+
+    fn publish_device(&self, device: &Device) {
+        let state = DeviceState {
+            present: device.present,
+            touch_needed: device.touch_needed,
+            last_outcome: device.last_outcome,
+            info: &device.info,
+        };
+        self.publish_json(device_topic(&self.config, device), &state);
+    }
+
+    /// Announce a sensor for whether any device is waiting, e.g. for a lamp to follow
+    fn publish_aggregate_discovery(&self) {
+        if !self.config.discovery {
+            return;
+        }
+        let object_id = sanitize(&self.config.client_id);
+        let discovery = Discovery {
+            name: "Touch needed",
+            unique_id: format!("{object_id}_touch_needed"),
+            object_id: format!("{object_id}_touch_needed"),
+            state_topic: topic(&self.config, "state"),
+            value_template: "{{ 'ON' if value_json.touch_needed else 'OFF' }}",
+            availability_topic: topic(&self.config, "status"),
+            device_class: None,
+            device: DiscoveryDevice {
+                identifiers: [object_id.clone()],
+                name: "U2F touch detector",
+                manufacturer: None,
+                model: None,
+                serial_number: None,
+            },
+        };
+        self.publish_json(self.discovery_topic(&object_id, "touch_needed"), &discovery);
+    }
+
+    /// Announce sensors for whether the device is connected and whether it is waiting
+    fn publish_discovery(&self, device: &Device) {
+        if !self.config.discovery {
+            return;
+        }
+        let info = &*device.info;
+        let object_id = format!("u2f_{}", device.id);
+        let model = models::find(info)
+            .map(|model| model.name)
+            .unwrap_or(&info.product);
+        let sensors = [
+            (
+                "touch_needed",
+                "Touch needed",
+                "{{ 'ON' if value_json.touch_needed else 'OFF' }}",
+                None,
+            ),
+            (
+                "present",
+                "Connected",
+                "{{ 'ON' if value_json.present else 'OFF' }}",
+                Some("connectivity"),
+            ),
+        ];
+        for (key, name, value_template, device_class) in sensors {
+            let discovery = Discovery {
+                name,
+                unique_id: format!("{object_id}_{key}"),
+                object_id: format!("{object_id}_{key}"),
+                state_topic: device_topic(&self.config, device),
+                value_template,
+                availability_topic: topic(&self.config, "status"),
+                device_class,
+                device: DiscoveryDevice {
+                    identifiers: [object_id.clone()],
+                    name: model,
+                    manufacturer: Some(&info.manufacturer),
+                    model: Some(model),
+                    serial_number: Some(&*info.serial).filter(|serial| !serial.is_empty()),
+                },
+            };
+            self.publish_json(self.discovery_topic(&object_id, key), &discovery);
+        }
+    }
+
+    fn discovery_topic(&self, object_id: &str, key: &str) -> String {
+        format!(
+            "{}/binary_sensor/{object_id}/{key}/config",
+            self.config.discovery_prefix
+        )
+    }
+
+    fn publish_json(&self, topic: String, payload: &impl Serialize) {
+        match serde_json::to_vec(payload) {
+            Ok(payload) => self.publish(topic, payload),
+            Err(err) => warn!(topic, "failed to serialize mqtt payload: {err}"),
+        }
+    }
+
+    /// Queue a retained message, dropping it if the queue is full rather than holding up events;
+    /// everything is republished on reconnecting
+    fn publish(&self, topic: String, payload: Vec<u8>) {
+        if let Err(err) = self
+            .client
+            .try_publish(&topic, QoS::AtLeastOnce, true, payload)
+        {
+            metrics::OUTPUT_FAILURES.increment(&["mqtt"]);
+            warn!(
+                topic,
+                "dropped mqtt message, the retained state may be stale: {err}"
+            );
+        }
+    }
+}
+
+fn topic(config: &Config, name: &str) -> String {
+    format!("{}/{name}", config.topic_prefix)
+}
+
+fn device_topic(config: &Config, device: &Device) -> String {
+    topic(config, &format!("devices/{}", device.id))
+}
+
+/// Make a device id safe to use as a topic level and Home Assistant object id
+fn sanitize(id: &str) -> String {
+    id.replace(
+        |c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'),
+        "_",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::testing::{self, touch_finished, touch_needed};
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+    };
+
+    /// A message published to the stub broker
+    #[derive(Debug)]
+    struct Message {
+        topic: String,
+        retain: bool,
+        payload: serde_json::Value,
+    }
+
+    /// Read an MQTT packet, returning the first byte of its fixed header and its body
+    fn read_packet(stream: &mut TcpStream) -> std::io::Result<(u8, Vec<u8>)> {
+        let mut byte = [0];
+        stream.read_exact(&mut byte)?;
+        let header = byte[0];
+        let (mut length, mut shift) = (0, 0);
+        loop {
+            stream.read_exact(&mut byte)?;
+            length |= usize::from(byte[0] & 0x7f) << shift;
+            shift += 7;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body)?;
+        Ok((header, body))
+    }
+
+    /// Accept a single client, acknowledging everything it sends and forwarding its publishes
+    fn broker(listener: TcpListener, tx: mpsc::Sender<Message>) {
+        let (mut stream, _) = listener.accept().unwrap();
+        while let Ok((header, body)) = read_packet(&mut stream) {
+            match header >> 4 {
+                // CONNECT, accepted
+                1 => stream.write_all(&[0x20, 2, 0, 0]).unwrap(),
+                // PUBLISH
+                3 => {
+                    let length = usize::from(u16::from_be_bytes([body[0], body[1]]));
+                    let topic = String::from_utf8(body[2..][..length].to_vec()).unwrap();
+                    let mut payload = &body[2 + length..];
+                    if header & 0b0110 != 0 {
+                        stream
+                            .write_all(&[0x40, 2, payload[0], payload[1]])
+                            .unwrap();
+                        payload = &payload[2..];
+                    }
+                    let message = Message {
+                        topic,
+                        retain: header & 1 == 1,
+                        payload: serde_json::from_slice(payload)
+                            .unwrap_or_else(|_| String::from_utf8_lossy(payload).into()),
+                    };
+                    if tx.send(message).is_err() {
+                        break;
+                    }
+                }
+                // PINGREQ
+                12 => stream.write_all(&[0xd0, 0]).unwrap(),
+                _ => {}
+            }
+        }
+    }
+
+    /// Skip messages until one is published to `topic`
+    fn until(messages: &mpsc::Receiver<Message>, topic: &str) -> Message {
+        loop {
+            let message = messages
+                .recv_timeout(Duration::from_secs(5))
+                .unwrap_or_else(|err| panic!("no message to {topic}: {err}"));
+            assert!(message.retain, "{message:?} is not retained");
+            if message.topic == topic {
+                return message;
+            }
+        }
+    }
+
+    #[test]
+    fn publishes_device_state() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, messages) = mpsc::channel();
+        std::thread::spawn(move || broker(listener, tx));
+
+        let config = Config {
+            enable: true,
+            host: "127.0.0.1".to_owned(),
+            port,
+            username: None,
+            password: None,
+            client_id: "test".to_owned(),
+            topic_prefix: "u2f".to_owned(),
+            discovery: true,
+            discovery_prefix: "homeassistant".to_owned(),
+        };
+        let (events, _output) = testing::spawn(move |rx| run(config, rx));
+
+        assert_eq!(until(&messages, "u2f/status").payload, "online");
+        let discovery = until(
+            &messages,
+            "homeassistant/binary_sensor/test/touch_needed/config",
+        );
+        assert_eq!(discovery.payload["state_topic"], "u2f/state");
+        // everything has been published once the aggregate state is
+        let state = until(&messages, "u2f/state");
+        assert_eq!(state.payload["touch_needed"], false);
+
+        let device = Arc::new(device::Info::fake("ABC/1"));
+        events
+            .send(Event::DeviceAdded {
+                device: device.clone(),
+            })
+            .unwrap();
+        let discovery = until(
+            &messages,
+            "homeassistant/binary_sensor/u2f_ABC_1/present/config",
+        );
+        assert_eq!(discovery.payload["state_topic"], "u2f/devices/ABC_1");
+        assert_eq!(discovery.payload["device"]["serial_number"], "ABC/1");
+        let state = until(&messages, "u2f/devices/ABC_1");
+        assert_eq!(state.payload["present"], true);
+        assert_eq!(state.payload["touch_needed"], false);
+        assert_eq!(state.payload["serial"], "ABC/1");
+
+        events.send(touch_needed(&device)).unwrap();
+        assert_eq!(
+            until(&messages, "u2f/devices/ABC_1").payload["touch_needed"],
+            true
+        );
+        let state = until(&messages, "u2f/state");
+        assert_eq!(state.payload["touch_needed"], true);
+        assert_eq!(state.payload["pending"], serde_json::json!(["ABC_1"]));
+
+        events.send(touch_finished(&device)).unwrap();
+        let state = until(&messages, "u2f/devices/ABC_1");
+        assert_eq!(state.payload["touch_needed"], false);
+        assert_eq!(state.payload["last_outcome"], "touched");
+
+        events.send(Event::DeviceRemoved { device }).unwrap();
+        assert_eq!(
+            until(&messages, "u2f/devices/ABC_1").payload["present"],
+            false
+        );
+
+        // devices without a serial get an id from where they are instead
+        let device = Arc::new(device::Info::fake(""));
+        events.send(Event::DeviceAdded { device }).unwrap();
+        let discovery = until(
+            &messages,
+            "homeassistant/binary_sensor/u2f_1050_0407_hidraw0/present/config",
+        );
+        assert!(discovery.payload["device"].get("serial_number").is_none());
+        assert_eq!(
+            until(&messages, "u2f/devices/1050_0407_hidraw0").payload["present"],
+            true
+        );
+    }
+}
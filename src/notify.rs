@@ -1,25 +1,181 @@
-use crate::config::ConfigMap;
+use crate::{
+    config::ConfigMap,
+    device,
+    event::{self, Event, Outcome},
+    exec,
+    message::Channel,
+    metrics, models,
+    portal::{self, Portal},
+    template::{self, Template},
+    timeouts::{self, Timeouts},
+};
 use camino::Utf8PathBuf;
-use eyre::Result;
-use notify_rust::{Notification, Timeout, Urgency};
+use eyre::{bail, Error, Result};
+use notify_rust::{Hint, Notification, NotificationHandle, Timeout, Urgency};
+use serde::Deserialize;
 use std::{
-    collections::{hash_map::Entry, HashMap},
-    sync::Arc,
+    collections::{hash_map::Entry, BTreeMap, HashMap},
+    io::Write,
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, Instant},
 };
-use tracing::warn;
+use tracing::{debug, info, info_span, warn};
+
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long escalation hooks and sound player commands may run before they are killed
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How soon after an action a close is taken to be the server removing the notification because
+/// of the action, rather than the user dismissing it
+const ACTION_CLOSE_WINDOW: Duration = Duration::from_secs(1);
 
 #[derive(confique::Config, Debug)]
 #[config(partial_attr(derive(Clone, Debug)))]
 #[config(partial_attr(serde(deny_unknown_fields, rename_all = "kebab-case")))]
 pub struct DeviceConfig {
+    /// Friendly name for this device, available in templates as `{alias}`
+    alias: Option<String>,
+
     /// Override notification heading for this device
-    heading: Option<String>,
+    heading: Option<Template>,
 
     /// Override notification message for this device
-    message: Option<String>,
+    message: Option<Template>,
 
     /// Override notification image for this device
     image: Option<Utf8PathBuf>,
+
+    /// Override notification urgency for this device
+    urgency: Option<UrgencyConfig>,
+
+    /// Override notification timeout for this device
+    timeout: Option<TimeoutConfig>,
+
+    /// Override notification category for this device
+    category: Option<String>,
+
+    /// Override application name for this device
+    app_name: Option<String>,
+
+    /// Extra hints for this device, merged over the global hints
+    hints: Option<Hints>,
+
+    /// Override how long this device waits for a touch before giving up
+    #[config(deserialize_with = crate::config::duration)]
+    expected_timeout: Option<Duration>,
+
+    /// Override how long to wait before notifying for this device
+    #[config(deserialize_with = crate::config::duration)]
+    delay: Option<Duration>,
+
+    /// Override the sound theme name for this device
+    sound_name: Option<String>,
+
+    /// Override the sound file for this device
+    sound_file: Option<Utf8PathBuf>,
+
+    /// Override the sound player command for this device
+    sound_command: Option<Vec<String>>,
+}
+
+#[derive(confique::Config, Debug)]
+#[config(partial_attr(derive(Clone, Debug)))]
+#[config(partial_attr(serde(deny_unknown_fields, rename_all = "kebab-case")))]
+pub struct ActionsConfig {
+    /// Label for the action hiding the notification for the current request, empty to disable
+    #[config(default = "Dismiss")]
+    dismiss: String,
+
+    /// Label for the action suppressing notifications for the device for a while, empty to
+    /// disable
+    #[config(default = "Snooze")]
+    snooze: String,
+
+    /// How long the snooze action suppresses notifications for
+    #[config(default = "15m", deserialize_with = crate::config::duration)]
+    snooze_duration: Duration,
+
+    /// Label for the action asking the device to identify itself (e.g. by flashing its LED),
+    /// empty to disable
+    #[config(default = "Identify")]
+    identify: String,
+
+    /// Label for the action cancelling the pending request, empty to disable
+    #[config(default = "Cancel request")]
+    cancel: String,
+}
+
+#[derive(confique::Config, Debug)]
+#[config(partial_attr(derive(Clone, Debug)))]
+#[config(partial_attr(serde(deny_unknown_fields, rename_all = "kebab-case")))]
+pub struct FollowUpConfig {
+    /// Outcomes which replace the notification with a follow-up once the request ends, any of
+    /// "touched", "timed-out", "denied", "cancelled" or "unknown"
+    #[config(default = [])]
+    outcomes: Vec<Outcome>,
+
+    /// Follow-up heading, supports the same placeholders as the main `message` plus `{outcome}`,
+    /// `{elapsed}` is how long the request took
+    #[config(default = "U2F Request {outcome}")]
+    heading: Template,
+
+    /// Follow-up message, supports the same placeholders as `heading`
+    #[config(default = "Device {serial}, after {elapsed}")]
+    message: Template,
+
+    /// How long the follow-up is shown for
+    #[config(default = "5s", deserialize_with = crate::config::duration)]
+    timeout: Duration,
+}
+
+#[derive(confique::Config, Debug)]
+#[config(partial_attr(derive(Clone, Debug)))]
+#[config(partial_attr(serde(deny_unknown_fields, rename_all = "kebab-case")))]
+pub struct SoundConfig {
+    /// Freedesktop sound theme name played by the notification server, e.g. "bell"
+    name: Option<String>,
+
+    /// Sound file played by the notification server
+    file: Option<Utf8PathBuf>,
+
+    /// Command playing the sound when the notification server cannot, or neither `name` nor
+    /// `file` are set, e.g. `["paplay", "/usr/share/sounds/freedesktop/stereo/bell.oga"]`
+    command: Option<Vec<String>>,
+
+    /// Play the sound again at this interval while the touch stays pending
+    #[config(deserialize_with = crate::config::positive_duration)]
+    repeat: Option<Duration>,
+}
+
+#[derive(confique::Config, Debug)]
+#[config(partial_attr(derive(Clone, Debug)))]
+#[config(partial_attr(serde(deny_unknown_fields, rename_all = "kebab-case")))]
+pub struct FallbackConfig {
+    /// Outputs used while no notification server is available, any of "bell" (ring the terminal
+    /// bell) or "message" (write the notification heading and message)
+    #[config(default = ["bell", "message"])]
+    outputs: Vec<FallbackOutput>,
+
+    /// Terminal the fallback outputs are written to, e.g. "/dev/tty" when running from a terminal;
+    /// without one there are no fallback outputs, as a service usually has no terminal
+    tty: Option<Utf8PathBuf>,
+
+    /// How long to wait before checking for a notification server again, doubling after each
+    /// failed check
+    #[config(default = "1s", deserialize_with = crate::config::duration)]
+    retry: Duration,
+
+    /// Longest wait between checks for a notification server
+    #[config(default = "1m", deserialize_with = crate::config::duration)]
+    max_retry: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum FallbackOutput {
+    Bell,
+    Message,
 }
 
 #[derive(confique::Config, Debug)]
@@ -30,80 +186,1484 @@ pub struct Config {
     #[config(default = false)]
     pub enable: bool,
 
-    /// Notification heading
+    /// How notifications are sent, either "notifications" (directly to the notification server)
+    /// or "portal" (through the XDG desktop portal, e.g. inside a sandbox); the portal doesn't
+    /// support timeouts, hints or sounds, so follow-ups stay until dismissed
+    #[config(default = "notifications")]
+    backend: Backend,
+
+    /// Notification heading, supports the same placeholders as `message`
     #[config(default = "U2F Touch Required")]
-    heading: String,
+    heading: Template,
+
+    /// Notification message, supports placeholders `{serial}`, `{product}`, `{manufacturer}`,
+    /// `{vid}`, `{pid}`, `{path}`, `{alias}`, `{pending}` (number of devices waiting), `{elapsed}`
+    /// and `{remaining}` (time until the device is expected to give up)
+    #[config(default = "Device {serial}")]
+    message: Template,
+
+    /// Update the notification every second to refresh `{elapsed}` and `{remaining}`, and show a
+    /// progress bar counting down to the expected timeout; not supported by the portal backend
+    #[config(default = false)]
+    live_update: bool,
+
+    /// How long devices wait for a touch before giving up, by default this is learned for each
+    /// device model
+    #[config(deserialize_with = crate::config::duration)]
+    expected_timeout: Option<Duration>,
+
+    /// How long to wait before notifying, requests finishing sooner than this (e.g. a quick
+    /// touch) don't show a notification at all
+    #[config(default = "0s", deserialize_with = crate::config::duration)]
+    delay: Duration,
+
+    /// Stages of reminders for touches that stay pending, e.g. `[{ after = "10s", heading =
+    /// "Still waiting!", urgency = "critical", sound-name = "bell" }]`; each stage can override
+    /// the `heading`, `message` and `urgency`, play a `sound-name` and run a `hook` command
+    #[config(default = [])]
+    escalation: Stages,
+
+    /// Rules muting or downgrading (to low urgency) notifications, the first matching rule
+    /// applies, e.g. `[{ from = "22:00", to = "07:00", action = "mute" }, { devices =
+    /// ["work-key"], do-not-disturb = true, action = "downgrade" }]`; rules can match a daily
+    /// time window, device serials or aliases, and whether the desktop is in do-not-disturb mode
+    #[config(default = [])]
+    rules: Vec<Rule>,
+
+    /// Show a single notification listing every device waiting for a touch, rather than one
+    /// notification per device
+    #[config(default = false)]
+    aggregate: bool,
 
-    // TODO: Maybe make this use a template string so it's possible to do something like the default
-    /// Notification message, default is "Device {serial}"
-    message: Option<String>,
+    /// Line for each device in the aggregated notification, supports the same placeholders as
+    /// `message`, `heading` is used with the longest waiting device
+    #[config(default = "{alias}, waiting {elapsed}")]
+    aggregate_line: Template,
 
-    /// Notification image
+    /// Notification image, defaults to a built-in image of the device when it is a known model
     image: Option<Utf8PathBuf>,
 
+    /// Recognize known device models by USB vendor/product id, to show a built-in image of the
+    /// device and a friendlier `{product}` name, applies to every output
+    #[config(default = true)]
+    pub builtin_models: bool,
+
+    /// Notification urgency, one of "low", "normal" or "critical"
+    #[config(default = "critical")]
+    urgency: UrgencyConfig,
+
+    /// How long until the notification expires, either "never", "default" (chosen by the
+    /// notification server) or a duration like "30s"
+    #[config(default = "never")]
+    timeout: TimeoutConfig,
+
+    /// Notification category, e.g. "device"
+    category: Option<String>,
+
+    /// Application name the notification is sent as
+    app_name: Option<String>,
+
+    /// Extra notification hints, e.g. `{ transient = true, desktop-entry = "foo" }`, names not
+    /// known by the notification spec are passed through as string or integer hints
+    hints: Option<Hints>,
+
+    /// Actions offered on the notification
+    #[config(nested)]
+    actions: ActionsConfig,
+
+    /// Notifications reporting how requests ended
+    #[config(nested)]
+    follow_up: FollowUpConfig,
+
+    /// Sound played when a touch is needed, not played for downgraded notifications
+    #[config(nested)]
+    sound: SoundConfig,
+
+    /// Outputs used instead of notifications while no notification server is available
+    #[config(nested)]
+    fallback: FallbackConfig,
+
     /// Override config for a specific device, indexed by device serial number
     #[config(nested)]
     devices: ConfigMap<DeviceConfig>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Backend {
+    Notifications,
+    Portal,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum UrgencyConfig {
+    Low,
+    Normal,
+    Critical,
+}
+
+impl From<UrgencyConfig> for Urgency {
+    fn from(urgency: UrgencyConfig) -> Self {
+        match urgency {
+            UrgencyConfig::Low => Self::Low,
+            UrgencyConfig::Normal => Self::Normal,
+            UrgencyConfig::Critical => Self::Critical,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct TimeoutConfig(Timeout);
+
+impl TryFrom<String> for TimeoutConfig {
+    type Error = Error;
+
+    #[culpa::try_fn]
+    fn try_from(value: String) -> Result<Self> {
+        match value.as_str() {
+            "never" => Self(Timeout::Never),
+            "default" => Self(Timeout::Default),
+            duration => Self(Timeout::from(humantime::parse_duration(duration)?)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum HintValue {
+    Bool(bool),
+    Int(i32),
+    String(String),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "BTreeMap<String, HintValue>")]
+pub(crate) struct Hints(BTreeMap<String, Hint>);
+
+impl TryFrom<BTreeMap<String, HintValue>> for Hints {
+    type Error = Error;
+
+    #[culpa::try_fn]
+    fn try_from(hints: BTreeMap<String, HintValue>) -> Result<Self> {
+        let mut parsed = BTreeMap::new();
+        for (name, value) in hints {
+            let known = match &value {
+                HintValue::Bool(value) => Hint::from_key_val(&name, &value.to_string()),
+                HintValue::Int(value) => Hint::from_key_val(&name, &value.to_string()),
+                HintValue::String(value) => Hint::from_key_val(&name, value),
+            };
+            let hint = match (known, value) {
+                (Ok(hint), _) => hint,
+                // notify-rust doesn't distinguish unknown names from invalid values except by message
+                (Err(err), _) if err != "unknown name" => {
+                    bail!("invalid value for hint {name}: {err}")
+                }
+                (Err(_), HintValue::Int(value)) => Hint::CustomInt(name.clone(), value),
+                (Err(_), HintValue::String(value)) => Hint::Custom(name.clone(), value),
+                (Err(_), HintValue::Bool(_)) => {
+                    bail!("custom hint {name} must be a string or integer")
+                }
+            };
+            parsed.insert(name, hint);
+        }
+        Self(parsed)
+    }
+}
+
+/// An escalation stage, applied once a touch has been pending for `after`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Stage {
+    #[serde(deserialize_with = "crate::config::duration")]
+    after: Duration,
+    heading: Option<Template>,
+    message: Option<Template>,
+    urgency: Option<UrgencyConfig>,
+    /// Freedesktop sound theme name to play when re-notifying, e.g. "bell"
+    sound_name: Option<String>,
+    /// Command to run, with details of the device in `U2F_TD_*` environment variables
+    hook: Option<Vec<String>>,
+}
+
+/// Escalation stages, sorted by when they apply
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "Vec<Stage>")]
+pub(crate) struct Stages(Vec<Stage>);
+
+impl From<Vec<Stage>> for Stages {
+    fn from(mut stages: Vec<Stage>) -> Self {
+        stages.sort_by_key(|stage| stage.after);
+        Self(stages)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum RuleAction {
+    Mute,
+    Downgrade,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Rule {
+    /// Start of a daily time window, in local time
+    from: Option<jiff::civil::Time>,
+    /// End of the daily time window, may be before `from` to wrap past midnight
+    to: Option<jiff::civil::Time>,
+    /// Serials or aliases of devices to match, all devices if unset
+    devices: Option<Vec<String>>,
+    /// Only match while the desktop is in do-not-disturb mode
+    #[serde(default)]
+    do_not_disturb: bool,
+    action: RuleAction,
+}
+
+impl Rule {
+    fn matches(&self, info: &device::Info, alias: Option<&str>, now: jiff::civil::Time) -> bool {
+        let in_window = match (self.from, self.to) {
+            (None, None) => true,
+            (Some(from), None) => from <= now,
+            (None, Some(to)) => now < to,
+            (Some(from), Some(to)) if from <= to => from <= now && now < to,
+            (Some(from), Some(to)) => from <= now || now < to,
+        };
+
+        let device = self.devices.as_ref().map_or(true, |devices| {
+            devices
+                .iter()
+                .any(|d| **d == *info.serial || Some(d.as_str()) == alias)
+        });
+
+        in_window && device && (!self.do_not_disturb || do_not_disturb())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Action {
+    Dismiss,
+    Snooze,
+    Identify,
+    Cancel,
+}
+
+impl Action {
+    const ALL: [Self; 4] = [Self::Dismiss, Self::Snooze, Self::Identify, Self::Cancel];
+
+    fn key(self) -> &'static str {
+        match self {
+            Self::Dismiss => "dismiss",
+            Self::Snooze => "snooze",
+            Self::Identify => "identify",
+            Self::Cancel => "cancel",
+        }
+    }
+
+    fn label(self, config: &ActionsConfig) -> &str {
+        match self {
+            Self::Dismiss => &config.dismiss,
+            Self::Snooze => &config.snooze,
+            Self::Identify => &config.identify,
+            Self::Cancel => &config.cancel,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum Input {
+    Event(Event),
+    /// An action was invoked on the notification with this id
+    Action {
+        id: u32,
+        key: String,
+    },
+    /// The notification with this id was closed by the user or server
+    Closed {
+        id: u32,
+    },
+    /// The signal listener started by the server check numbered `generation` stopped
+    SignalsLost {
+        generation: u64,
+        err: Error,
+    },
+}
+
+struct Active {
+    device: Arc<device::Info>,
+    channel: Channel,
+    started: Instant,
+    /// When to notify, `None` if there is no delay or once it has passed
+    delayed_until: Option<Instant>,
+    /// Whether the request was notified, false until any delay has passed
+    shown: bool,
+    /// Whether the device was snoozed or dismissed for this request
+    hidden: bool,
+    /// Whether a rule lowered the urgency of notifications for this request
+    downgraded: bool,
+    /// How many escalation stages have been applied
+    stage: usize,
+    /// When to next play the sound, `None` if there is no sound or it doesn't repeat
+    next_sound: Option<Instant>,
+    /// The per-device notification, `None` if aggregating, hidden, or it failed to show
+    notification: Option<Handle>,
+}
+
+impl Active {
+    /// Whether the sound should be played now, scheduling the next repeat if so
+    fn sound_due(&mut self, config: &Config, now: Instant) -> bool {
+        if self.downgraded || self.next_sound.map_or(true, |at| at > now) {
+            return false;
+        }
+        self.next_sound = config.sound.repeat.map(|repeat| now + repeat);
+        true
+    }
+
+    fn hide(&mut self) {
+        self.hidden = true;
+        if let Some(notification) = self.notification.take() {
+            notification.close();
+        }
+    }
+}
+
+/// A shown notification, from either backend
+enum Handle {
+    Server(NotificationHandle),
+    Portal(portal::Handle),
+}
+
+impl Handle {
+    fn id(&self) -> u32 {
+        match self {
+            Self::Server(handle) => handle.id(),
+            Self::Portal(handle) => handle.id(),
+        }
+    }
+
+    /// Show `notification` in place of this one
+    fn replace(&mut self, notification: Notification) {
+        match self {
+            Self::Server(handle) => {
+                **handle = notification;
+                handle.update();
+            }
+            Self::Portal(handle) => handle.replace(notification),
+        }
+    }
+
+    fn close(self) {
+        match self {
+            Self::Server(handle) => handle.close(),
+            Self::Portal(handle) => handle.close(),
+        }
+    }
+}
+
+/// Whether the notification server (or portal) is available and what it can do, rechecked with
+/// backoff while it is unavailable
+struct Server {
+    backend: Backend,
+    /// Capabilities advertised by the server, `None` while it is unavailable
+    capabilities: Option<Vec<String>>,
+    /// Connection to the portal, if that is the backend and it is available
+    portal: Option<Portal>,
+    /// When to next check for the server while it is unavailable
+    retry_at: Instant,
+    backoff: Duration,
+    /// Where action and close signals are forwarded to
+    inputs: mpsc::Sender<Input>,
+    /// The connection listening for signals, restarted by the next check once it stops
+    signals: Option<Signals>,
+    /// Counts the signal listeners started, so a stale listener stopping is ignored
+    generation: u64,
+}
+
+/// A session bus connection with a thread forwarding its signals
+struct Signals {
+    connection: zbus::blocking::Connection,
+    thread: std::thread::JoinHandle<()>,
+}
+
+impl Server {
+    fn connect(backend: Backend, config: &FallbackConfig, inputs: mpsc::Sender<Input>) -> Self {
+        let mut server = Self {
+            backend,
+            capabilities: None,
+            portal: None,
+            retry_at: Instant::now(),
+            backoff: config.retry,
+            inputs,
+            signals: None,
+            generation: 0,
+        };
+        server.check(config);
+        server
+    }
+
+    /// Query the server information and capabilities, returning whether the server has just
+    /// become available
+    fn check(&mut self, config: &FallbackConfig) -> bool {
+        match self.query() {
+            Ok(capabilities) => {
+                self.backoff = config.retry;
+                self.capabilities.replace(capabilities).is_none()
+            }
+            Err(err) => {
+                self.unavailable(config, err);
+                false
+            }
+        }
+    }
+
+    #[culpa::try_fn]
+    fn query(&mut self) -> Result<Vec<String>> {
+        let connection = self.signals_connection()?;
+        match self.backend {
+            Backend::Notifications => {
+                let info = notify_rust::get_server_information()?;
+                let capabilities = notify_rust::get_capabilities()?;
+                info!(
+                    name = info.name,
+                    vendor = info.vendor,
+                    version = info.version,
+                    ?capabilities,
+                    "found notification server"
+                );
+                capabilities
+            }
+            Backend::Portal => {
+                // the portal only sends actions to the connection that added the notification
+                self.portal = Some(Portal::new(&connection)?);
+                Vec::new()
+            }
+        }
+    }
+
+    /// The connection action and close signals are forwarded from, starting a new listener if
+    /// there is none yet or it stopped
+    #[culpa::try_fn]
+    fn signals_connection(&mut self) -> Result<zbus::blocking::Connection> {
+        match &self.signals {
+            Some(signals) if !signals.thread.is_finished() => signals.connection.clone(),
+            _ => {
+                let connection = zbus::blocking::Connection::session()?;
+                self.generation += 1;
+                let generation = self.generation;
+                let backend = self.backend;
+                let inputs = self.inputs.clone();
+                let listener = connection.clone();
+                let thread = std::thread::spawn(move || {
+                    if let Err(err) = listen_signals(backend, &listener, &inputs) {
+                        let _ = inputs.send(Input::SignalsLost { generation, err });
+                    }
+                });
+                self.signals = Some(Signals {
+                    connection: connection.clone(),
+                    thread,
+                });
+                connection
+            }
+        }
+    }
+
+    fn unavailable(&mut self, config: &FallbackConfig, err: Error) {
+        self.portal = None;
+        metrics::OUTPUT_FAILURES.increment(&["notify"]);
+        if self.capabilities.take().is_some() || self.backoff == config.retry {
+            warn!(
+                ?err,
+                "notification server unavailable, using fallback outputs"
+            );
+        } else {
+            debug!(?err, "notification server still unavailable");
+        }
+        self.retry_at = Instant::now() + self.backoff;
+        self.backoff = (self.backoff * 2).min(config.max_retry);
+    }
+
+    fn available(&self) -> bool {
+        self.capabilities.is_some()
+    }
+
+    fn plays_sounds(&self) -> bool {
+        self.capabilities
+            .as_ref()
+            .is_some_and(|capabilities| capabilities.iter().any(|c| c == "sound"))
+    }
+
+    /// Show `notification`, or fire the fallback outputs if the server is unavailable
+    fn show(&mut self, config: &Config, notification: Notification) -> Option<Handle> {
+        if self.available() {
+            let result = match &self.portal {
+                Some(portal) => portal.show(notification.clone()).map(Handle::Portal),
+                None => notification.show().map(Handle::Server).map_err(Error::from),
+            };
+            match result {
+                Ok(handle) => return Some(handle),
+                Err(err) => self.unavailable(&config.fallback, err),
+            }
+        }
+        fallback(&config.fallback, &notification);
+        None
+    }
+}
+
 #[culpa::try_fn]
-pub(crate) fn run(
-    config: Config,
-    mut rx: tokio::sync::broadcast::Receiver<(Arc<str>, bool)>,
-) -> Result<()> {
-    let mut active = HashMap::new();
+pub(crate) fn run(config: Config, mut rx: event::Receiver) -> Result<()> {
+    let (tx, inputs) = mpsc::channel();
 
-    while let Ok((serial, needed)) = rx.blocking_recv() {
-        match (needed, active.entry(serial.clone())) {
-            (true, Entry::Vacant(entry)) => {
-                let device = config.devices.inner.get(&*serial);
+    std::thread::spawn({
+        let tx = tx.clone();
+        move || {
+            while let Some(event) = event::recv(&mut rx) {
+                if tx.send(Input::Event(event)).is_err() {
+                    break;
+                }
+            }
+        }
+    });
 
-                let summary = device
-                    .and_then(|d| d.heading.as_deref())
-                    .unwrap_or(&config.heading);
+    let mut active = HashMap::<Arc<str>, Active>::new();
+    let mut aggregate = None;
+    let mut snoozed = HashMap::new();
+    let mut timeouts = Timeouts::load();
+    let mut server = Server::connect(config.backend, &config.fallback, tx);
+    let mut next_update = Instant::now() + UPDATE_INTERVAL;
+    // the notification id and time of the last action invoked
+    let mut last_action = None;
 
-                let body_tmp;
-                let body = match device
-                    .and_then(|d| d.message.as_deref())
-                    .or(config.message.as_deref())
-                {
-                    Some(message) => message,
-                    None => {
-                        // TODO(rustc 1.79): no tmp needed
-                        body_tmp = format!("Device {serial}");
-                        &body_tmp
+    loop {
+        let now = Instant::now();
+        let pending = active.len();
+        let mut newly_shown = false;
+        let mut escalated = false;
+        let mut sound = None;
+        let appeared =
+            !server.available() && server.retry_at <= now && server.check(&config.fallback);
+        for current in active.values_mut() {
+            // show notifications for requests that were only signalled by the fallback outputs
+            let mut renotify = appeared && !config.aggregate && current.notification.is_none();
+            if current.delayed_until.is_some_and(|until| until <= now) {
+                current.delayed_until = None;
+            }
+
+            if current.hidden || current.delayed_until.is_some() {
+                continue;
+            }
+
+            if !current.shown {
+                current.shown = true;
+                newly_shown = true;
+                renotify = true;
+            }
+
+            let _guard = info_span!("device", %current.device.serial).entered();
+
+            let elapsed = now - current.started;
+            let mut stage_sound = None;
+            if config
+                .escalation
+                .0
+                .get(current.stage)
+                .is_some_and(|stage| elapsed >= stage.after)
+            {
+                // skip over any stages that have also passed, e.g. after the delay
+                current.stage = config.escalation.0.partition_point(|s| s.after <= elapsed);
+                escalated = true;
+                renotify = true;
+
+                info!(stage = current.stage, "escalating notification");
+
+                let stage = &config.escalation.0[current.stage - 1];
+                if let Some(hook) = &stage.hook {
+                    run_hook(hook, &current.device, elapsed);
+                }
+                stage_sound = stage.sound_name.as_ref().filter(|_| !current.downgraded);
+            }
+
+            let sound_due = current.sound_due(&config, now);
+            if config.aggregate {
+                if sound_due {
+                    sound.get_or_insert_with(|| current.device.clone());
+                }
+                continue;
+            }
+
+            if renotify || sound_due {
+                let mut notification = build(&config, &timeouts, current, pending);
+                if let Some(stage_sound) = stage_sound {
+                    notification.sound_name(stage_sound);
+                } else if sound_due {
+                    play_sound(&config, &server, &current.device, &mut notification);
+                }
+                match current.notification.take() {
+                    // a repeated sound only needs the notification replaced in place, closing it
+                    // and showing a new one would flicker
+                    Some(mut existing) if !renotify => {
+                        existing.replace(notification);
+                        current.notification = Some(existing);
                     }
-                };
+                    existing => {
+                        if let Some(existing) = existing {
+                            existing.close();
+                        }
+                        current.notification = server.show(&config, notification);
+                    }
+                }
+            }
+        }
+        if config.aggregate && (appeared || newly_shown || escalated || sound.is_some()) {
+            // without a server, a newly waiting device must renotify to fire the fallback outputs
+            let renotify = escalated || (newly_shown && !server.available());
+            update_aggregate(
+                &config,
+                &mut server,
+                &timeouts,
+                &active,
+                &mut aggregate,
+                renotify,
+                sound.as_deref(),
+            );
+        }
+
+        let wake = active
+            .values()
+            .filter_map(|a| {
+                a.delayed_until.or_else(|| {
+                    let stage = config.escalation.0.get(a.stage)?;
+                    (!a.hidden).then(|| a.started + stage.after)
+                })
+            })
+            .chain((!server.available()).then_some(server.retry_at))
+            .fold(next_update, Instant::min);
+
+        let input = match inputs.recv_timeout(wake.saturating_duration_since(now)) {
+            Ok(input) => input,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                if Instant::now() < next_update {
+                    continue;
+                }
+                next_update = Instant::now() + UPDATE_INTERVAL;
+                // the portal may show every replacement as a new notification
+                if config.live_update && config.backend != Backend::Portal {
+                    let pending = active.len();
+                    for current in active.values_mut() {
+                        if let Some(mut notification) = current.notification.take() {
+                            notification.replace(build(&config, &timeouts, current, pending));
+                            current.notification = Some(notification);
+                        }
+                    }
+                    if config.aggregate {
+                        update_aggregate(
+                            &config,
+                            &mut server,
+                            &timeouts,
+                            &active,
+                            &mut aggregate,
+                            false,
+                            None,
+                        );
+                    }
+                }
+                continue;
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
 
-                let image = device
-                    .and_then(|d| d.image.as_deref())
-                    .or(config.image.as_deref());
+        match input {
+            Input::Event(Event::TouchNeeded { device, channel }) => {
+                let Entry::Vacant(entry) = active.entry(device.id()) else {
+                    continue;
+                };
 
-                let mut notification = Notification::new();
+                let now = Instant::now();
+                snoozed.retain(|_, until| *until > now);
+                let snoozed = snoozed.contains_key(&device.id());
+                let rule = (!snoozed)
+                    .then(|| matching_rule(&config, &device))
+                    .flatten();
+                let hidden = snoozed || rule == Some(RuleAction::Mute);
+                let delay = config
+                    .devices
+                    .inner
+                    .get(&*device.serial)
+                    .and_then(|d| d.delay)
+                    .unwrap_or(config.delay);
+                // shown (and the sound played) once the delay passes, right away if there is none
+                let delayed_until = (!hidden && !delay.is_zero()).then(|| now + delay);
+                let next_sound = has_sound(&config, &device).then_some(now + delay);
 
-                notification
-                    .timeout(Timeout::Never)
-                    .urgency(Urgency::Critical)
-                    .summary(summary)
-                    .body(body);
+                let current = entry.insert(Active {
+                    device,
+                    channel,
+                    started: now,
+                    delayed_until,
+                    shown: false,
+                    hidden,
+                    downgraded: rule == Some(RuleAction::Downgrade),
+                    stage: 0,
+                    next_sound,
+                    notification: None,
+                });
 
-                if let Some(image) = image {
-                    notification.image_path(image.as_str());
+                let _guard = info_span!("device", %current.device.serial).entered();
+                if snoozed {
+                    info!("device is snoozed, not notifying");
+                } else if hidden {
+                    info!("notification muted by rule");
+                }
+            }
+            Input::Event(Event::TouchFinished {
+                device,
+                outcome,
+                duration,
+            }) => {
+                if outcome == Outcome::TimedOut && expected_timeout(&config, &device).is_none() {
+                    timeouts.record(&device, duration);
+                }
+                let pending = active.len();
+                let Some(current) = active.remove(&device.id()) else {
+                    continue;
+                };
+                if current.delayed_until.is_some() {
+                    let suppressed = metrics::NOTIFICATIONS_SUPPRESSED.increment();
+                    debug!(
+                        device.serial = %device.serial,
+                        suppressed, "request finished within delay, notification suppressed"
+                    );
+                }
+                let follow_up = config.follow_up.outcomes.contains(&outcome)
+                    && !current.hidden
+                    && current.shown;
+                match current.notification {
+                    Some(mut notification) if follow_up => {
+                        notification.replace(build_follow_up(
+                            &config, &device, pending, outcome, duration,
+                        ));
+                    }
+                    Some(notification) => notification.close(),
+                    None if follow_up && config.aggregate => {
+                        server.show(
+                            &config,
+                            build_follow_up(&config, &device, pending, outcome, duration),
+                        );
+                    }
+                    None => {}
+                }
+            }
+            Input::Event(Event::DeviceRemoved { device }) => {
+                if let Some(notification) = active
+                    .remove(&device.id())
+                    .and_then(|current| current.notification)
+                {
+                    notification.close();
+                }
+            }
+            Input::Event(Event::SnoozeRequested { device, duration }) => {
+                let _guard = info_span!("device", %device.serial).entered();
+                info!(?duration, "snoozing device");
+                let Some(until) = Instant::now().checked_add(duration) else {
+                    warn!(?duration, "snooze too long, ignoring it");
+                    continue;
+                };
+                snoozed.insert(device.id(), until);
+                if let Some(current) = active.get_mut(&device.id()) {
+                    current.hide();
                 }
+            }
+            Input::Event(Event::DeviceAdded { .. }) => {}
+            Input::Action { id, key } => {
+                let Some(action) = Action::ALL.into_iter().find(|a| a.key() == key) else {
+                    debug!(key, "ignoring unknown action");
+                    continue;
+                };
+
+                // actions on the aggregated notification apply to every device listed in it
+                let is_aggregate = aggregate.as_ref().is_some_and(|n: &Handle| n.id() == id);
+                let targets = active.values_mut().filter(|a| match &a.notification {
+                    Some(notification) => notification.id() == id,
+                    None => is_aggregate && !a.hidden && a.shown,
+                });
 
-                match notification.show() {
-                    Ok(handle) => {
-                        entry.insert(handle);
+                for current in targets {
+                    let _guard = info_span!("device", %current.device.serial).entered();
+                    perform(&config, action, current, &mut snoozed);
+                }
+                last_action = Some((id, Instant::now()));
+            }
+            Input::Closed { id } => {
+                // servers that ignore the resident hint close the notification after any action,
+                // which shouldn't stop escalations or follow-ups for a request still pending
+                let dismissed = !last_action.is_some_and(|(action_id, at)| {
+                    action_id == id && at.elapsed() < ACTION_CLOSE_WINDOW
+                });
+                if aggregate.as_ref().is_some_and(|n| n.id() == id) {
+                    aggregate = None;
+                    for current in active.values_mut() {
+                        current.hidden |= dismissed;
                     }
-                    Err(err) => {
-                        warn!(?err, "failed to show notification");
+                }
+                for current in active.values_mut() {
+                    if current.notification.as_ref().is_some_and(|n| n.id() == id) {
+                        current.notification = None;
+                        current.hidden |= dismissed;
                     }
                 }
             }
-            (false, Entry::Occupied(entry)) => {
-                entry.remove().close();
+            Input::SignalsLost { generation, err } => {
+                if generation == server.generation {
+                    // actions and closes are lost too, so recheck the server with backoff
+                    server.unavailable(&config.fallback, err.wrap_err("stopped receiving signals"));
+                }
+            }
+        }
+
+        if config.aggregate {
+            update_aggregate(
+                &config,
+                &mut server,
+                &timeouts,
+                &active,
+                &mut aggregate,
+                false,
+                None,
+            );
+        }
+    }
+}
+
+fn perform(
+    config: &Config,
+    action: Action,
+    current: &mut Active,
+    snoozed: &mut HashMap<Arc<str>, Instant>,
+) {
+    match action {
+        Action::Dismiss => {
+            info!("dismissing notification");
+            current.hide();
+        }
+        Action::Snooze => {
+            info!(duration = ?config.actions.snooze_duration, "snoozing device");
+            snoozed.insert(
+                current.device.id(),
+                Instant::now() + config.actions.snooze_duration,
+            );
+            current.hide();
+        }
+        Action::Identify => {
+            info!("asking device to identify itself");
+            std::thread::spawn({
+                let device = current.device.clone();
+                move || {
+                    if let Err(err) = device.wink() {
+                        warn!("failed to identify device: {err:?}");
+                    }
+                }
+            });
+        }
+        Action::Cancel => {
+            info!("cancelling pending request");
+            if let Err(err) = current.device.cancel(current.channel) {
+                warn!("failed to cancel request: {err:?}");
+            }
+        }
+    }
+}
+
+/// Show, update or close the aggregated notification to match the set of waiting devices, if
+/// `renotify` any existing notification is replaced by a new one to draw attention again, playing
+/// the `sound` for a device updates it in place; while the server is unavailable the fallback
+/// outputs only fire when renotifying or playing a sound
+fn update_aggregate(
+    config: &Config,
+    server: &mut Server,
+    timeouts: &Timeouts,
+    active: &HashMap<Arc<str>, Active>,
+    aggregate: &mut Option<Handle>,
+    renotify: bool,
+    sound: Option<&device::Info>,
+) {
+    let mut waiting: Vec<_> = active.values().filter(|a| !a.hidden && a.shown).collect();
+    waiting.sort_by_key(|a| a.started);
+
+    if waiting.is_empty() {
+        if let Some(notification) = aggregate.take() {
+            notification.close();
+        }
+        return;
+    }
+
+    let mut built = build_aggregate(config, timeouts, &waiting);
+    if renotify || sound.is_some() {
+        // a repeated sound alone updates the notification in place rather than replacing it
+        if renotify {
+            if let Some(notification) = aggregate.take() {
+                notification.close();
+            }
+        }
+        let stage = waiting.iter().map(|a| a.stage).max().unwrap_or(0);
+        let stage_sound = current_stage(config, stage)
+            .and_then(|s| s.sound_name.as_ref())
+            .filter(|_| renotify);
+        if let Some(stage_sound) = stage_sound {
+            built.sound_name(stage_sound);
+        } else if let Some(info) = sound {
+            play_sound(config, server, info, &mut built);
+        }
+    }
+    match aggregate {
+        Some(notification) => notification.replace(built),
+        None if server.available() || renotify || sound.is_some() => {
+            *aggregate = server.show(config, built);
+        }
+        None => {}
+    }
+}
+
+/// The action of the first rule matching `info` right now, if any
+fn matching_rule(config: &Config, info: &device::Info) -> Option<RuleAction> {
+    let alias = config
+        .devices
+        .inner
+        .get(&*info.serial)
+        .and_then(|d| d.alias.as_deref());
+    let now = jiff::Zoned::now().time();
+    config
+        .rules
+        .iter()
+        .find(|rule| rule.matches(info, alias, now))
+        .map(|rule| rule.action)
+}
+
+/// Whether the desktop is in do-not-disturb mode, according to either the `Inhibited` property
+/// some notification servers (e.g. KDE) expose, or dunst's `paused` property
+fn do_not_disturb() -> bool {
+    /// Shared by every rule evaluation rather than connecting each time, dropped on errors so the
+    /// next evaluation reconnects
+    static SESSION: Mutex<Option<zbus::blocking::Connection>> = Mutex::new(None);
+
+    #[culpa::try_fn]
+    fn property(
+        connection: &zbus::blocking::Connection,
+        interface: &'static str,
+        name: &str,
+    ) -> Result<bool> {
+        let proxy = zbus::blocking::Proxy::new(
+            connection,
+            "org.freedesktop.Notifications",
+            "/org/freedesktop/Notifications",
+            interface,
+        )?;
+        proxy.get_property(name)?
+    }
+
+    let mut session = SESSION.lock().unwrap();
+    let connection = match &*session {
+        Some(connection) => connection.clone(),
+        None => match zbus::blocking::Connection::session() {
+            Ok(connection) => session.insert(connection).clone(),
+            Err(err) => {
+                debug!("cannot connect to session bus: {err:?}");
+                return false;
+            }
+        },
+    };
+
+    [
+        ("org.freedesktop.Notifications", "Inhibited"),
+        ("org.dunstproject.cmd0", "paused"),
+    ]
+    .into_iter()
+    .any(
+        |(interface, name)| match property(&connection, interface, name) {
+            Ok(value) => value,
+            Err(err) => {
+                debug!("cannot get {interface}.{name}: {err:?}");
+                if matches!(
+                    err.downcast_ref::<zbus::Error>(),
+                    Some(zbus::Error::InputOutput(_))
+                ) {
+                    *session = None;
+                }
+                false
+            }
+        },
+    )
+}
+
+/// The configured timeout for `info`, if any
+fn expected_timeout(config: &Config, info: &device::Info) -> Option<Duration> {
+    config
+        .devices
+        .inner
+        .get(&*info.serial)
+        .and_then(|d| d.expected_timeout)
+        .or(config.expected_timeout)
+}
+
+/// Signal a notification on the fallback outputs
+fn fallback(config: &FallbackConfig, notification: &Notification) {
+    let Some(tty) = &config.tty else {
+        return;
+    };
+    if config.outputs.is_empty() {
+        return;
+    }
+
+    let mut output = String::new();
+    for kind in &config.outputs {
+        match kind {
+            FallbackOutput::Bell => output.push('\x07'),
+            FallbackOutput::Message => output.push_str(&format!(
+                "{}: {}\n",
+                notification.summary, notification.body
+            )),
+        }
+    }
+
+    let result = std::fs::OpenOptions::new()
+        .append(true)
+        .open(tty)
+        .and_then(|mut tty| tty.write_all(output.as_bytes()));
+    if let Err(err) = result {
+        metrics::OUTPUT_FAILURES.increment(&["notify"]);
+        warn!(%tty, "failed to write fallback output: {err}");
+    }
+}
+
+/// Template context for `info` when it has been waiting for `elapsed`, along with how long it is
+/// expected to wait in total
+fn context<'a>(
+    config: &'a Config,
+    timeouts: &Timeouts,
+    info: &'a device::Info,
+    pending: usize,
+    elapsed: Duration,
+) -> (template::Context<'a>, Duration) {
+    let device = config.devices.inner.get(&*info.serial);
+
+    let expected = expected_timeout(config, info)
+        .or_else(|| timeouts.get(info))
+        .unwrap_or(timeouts::DEFAULT_TIMEOUT);
+
+    let context = template::Context {
+        device: info,
+        alias: device.and_then(|d| d.alias.as_deref()),
+        model: models::find(info).map(|model| model.name),
+        pending,
+        elapsed,
+        remaining: expected.saturating_sub(elapsed),
+        outcome: None,
+    };
+
+    (context, expected)
+}
+
+/// The most recently applied escalation stage, if any
+fn current_stage(config: &Config, stage: usize) -> Option<&Stage> {
+    config.escalation.0.get(stage.checked_sub(1)?)
+}
+
+fn build(config: &Config, timeouts: &Timeouts, current: &Active, pending: usize) -> Notification {
+    let info = &current.device;
+    let device = config.devices.inner.get(&*info.serial);
+    let stage = current_stage(config, current.stage);
+    let elapsed = current.started.elapsed();
+    let (context, expected) = context(config, timeouts, info, pending, elapsed);
+
+    let summary = stage
+        .and_then(|s| s.heading.as_ref())
+        .or(device.and_then(|d| d.heading.as_ref()))
+        .unwrap_or(&config.heading)
+        .render(&context);
+
+    let body = stage
+        .and_then(|s| s.message.as_ref())
+        .or(device.and_then(|d| d.message.as_ref()))
+        .unwrap_or(&config.message)
+        .render(&context);
+
+    let urgency = if current.downgraded {
+        UrgencyConfig::Low
+    } else {
+        stage
+            .and_then(|s| s.urgency)
+            .or(device.and_then(|d| d.urgency))
+            .unwrap_or(config.urgency)
+    };
+
+    let timeout = device.and_then(|d| d.timeout).unwrap_or(config.timeout);
+
+    let mut notification = base(config, info);
+
+    notification
+        .timeout(timeout.0)
+        .urgency(urgency.into())
+        .summary(&summary)
+        .body(&body);
+
+    decorate(config, &mut notification, context.remaining, expected);
+
+    notification
+}
+
+/// A single notification listing all `waiting` devices, oldest first
+fn build_aggregate(config: &Config, timeouts: &Timeouts, waiting: &[&Active]) -> Notification {
+    let pending = waiting.len();
+    let oldest = waiting[0];
+    let (context, expected) = context(
+        config,
+        timeouts,
+        &oldest.device,
+        pending,
+        oldest.started.elapsed(),
+    );
+
+    let stage = current_stage(config, waiting.iter().map(|a| a.stage).max().unwrap_or(0));
+
+    let summary = stage
+        .and_then(|s| s.heading.as_ref())
+        .unwrap_or(&config.heading)
+        .render(&context);
+
+    let urgency = if waiting.iter().all(|a| a.downgraded) {
+        UrgencyConfig::Low
+    } else {
+        stage.and_then(|s| s.urgency).unwrap_or(config.urgency)
+    };
+
+    let body = waiting
+        .iter()
+        .map(|current| {
+            let elapsed = current.started.elapsed();
+            let (context, _) = self::context(config, timeouts, &current.device, pending, elapsed);
+            config.aggregate_line.render(&context)
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let mut notification = base(config, &oldest.device);
+
+    notification
+        .timeout(config.timeout.0)
+        .urgency(urgency.into())
+        .summary(&summary)
+        .body(&body);
+
+    decorate(config, &mut notification, context.remaining, expected);
+
+    notification
+}
+
+/// Add the progress bar and actions to a notification of a waiting request
+fn decorate(
+    config: &Config,
+    notification: &mut Notification,
+    remaining: Duration,
+    expected: Duration,
+) {
+    if config.live_update {
+        let progress = remaining.as_millis() * 100 / expected.as_millis().max(1);
+        notification.hint(Hint::CustomInt("value".to_owned(), progress as i32));
+    }
+
+    let mut has_actions = false;
+    for action in Action::ALL {
+        let label = action.label(&config.actions);
+        if !label.is_empty() {
+            notification.action(action.key(), label);
+            has_actions = true;
+        }
+    }
+    // servers close notifications once an action is invoked unless they are resident, e.g.
+    // identifying the device would remove the notification while the touch is still pending
+    let resident = notification
+        .hints
+        .iter()
+        .any(|hint| matches!(hint, Hint::Resident(_)));
+    if has_actions && !resident {
+        notification.hint(Hint::Resident(true));
+    }
+}
+
+/// A short-lived notification reporting how the request ended
+fn build_follow_up(
+    config: &Config,
+    info: &device::Info,
+    pending: usize,
+    outcome: Outcome,
+    duration: Duration,
+) -> Notification {
+    let device = config.devices.inner.get(&*info.serial);
+
+    let context = template::Context {
+        device: info,
+        alias: device.and_then(|d| d.alias.as_deref()),
+        model: models::find(info).map(|model| model.name),
+        pending,
+        elapsed: duration,
+        remaining: Duration::ZERO,
+        outcome: Some(outcome),
+    };
+
+    let mut notification = base(config, info);
+
+    notification
+        .timeout(config.follow_up.timeout)
+        .urgency(Urgency::Normal)
+        .summary(&config.follow_up.heading.render(&context))
+        .body(&config.follow_up.message.render(&context));
+
+    notification
+}
+
+/// Settings shared between the touch notification and its follow-up
+fn base(config: &Config, info: &device::Info) -> Notification {
+    let device = config.devices.inner.get(&*info.serial);
+
+    let image = device
+        .and_then(|d| d.image.as_deref())
+        .or(config.image.as_deref())
+        .or_else(|| models::find(info)?.icon.path().map(|path| path.as_path()));
+
+    let category = device
+        .and_then(|d| d.category.as_deref())
+        .or(config.category.as_deref());
+
+    let app_name = device
+        .and_then(|d| d.app_name.as_deref())
+        .or(config.app_name.as_deref());
+
+    let mut notification = Notification::new();
+
+    if let Some(image) = image {
+        notification.image_path(image.as_str());
+    }
+
+    if let Some(category) = category {
+        notification.hint(Hint::Category(category.to_owned()));
+    }
+
+    if let Some(app_name) = app_name {
+        notification.appname(app_name);
+    }
+
+    // merge by name so that device hints replace global hints rather than being sent as well
+    let hints: BTreeMap<_, _> = config
+        .hints
+        .iter()
+        .chain(device.and_then(|d| d.hints.as_ref()))
+        .flat_map(|hints| &hints.0)
+        .collect();
+    for hint in hints.into_values() {
+        notification.hint(hint.clone());
+    }
+
+    notification
+}
+
+/// Whether any sound is configured for `info`
+fn has_sound(config: &Config, info: &device::Info) -> bool {
+    let device = config.devices.inner.get(&*info.serial);
+    device.is_some_and(|d| {
+        d.sound_name.is_some() || d.sound_file.is_some() || d.sound_command.is_some()
+    }) || config.sound.name.is_some()
+        || config.sound.file.is_some()
+        || config.sound.command.is_some()
+}
+
+/// Play the sound for `info`, by adding hints to `notification` if the notification server can
+/// play it, otherwise by running the player command
+fn play_sound(
+    config: &Config,
+    server: &Server,
+    info: &device::Info,
+    notification: &mut Notification,
+) {
+    let device = config.devices.inner.get(&*info.serial);
+
+    let name = device
+        .and_then(|d| d.sound_name.as_deref())
+        .or(config.sound.name.as_deref());
+
+    let file = device
+        .and_then(|d| d.sound_file.as_deref())
+        .or(config.sound.file.as_deref());
+
+    let command = device
+        .and_then(|d| d.sound_command.as_deref())
+        .or(config.sound.command.as_deref());
+
+    if (name.is_some() || file.is_some()) && server.plays_sounds() {
+        debug!("playing sound through notification server");
+        if let Some(name) = name {
+            notification.sound_name(name);
+        }
+        if let Some(file) = file {
+            notification.hint(Hint::SoundFile(file.to_string()));
+        }
+    } else if let Some((program, args)) = command.and_then(|c| c.split_first()) {
+        debug!("playing sound through player command");
+        let mut command = std::process::Command::new(program);
+        command.args(args);
+        let job = exec::Job::new("notify", "sound", info, command);
+        std::thread::spawn(move || job.run(COMMAND_TIMEOUT));
+    } else {
+        debug!("notification server cannot play sounds and there is no player command");
+    }
+}
+
+/// Run an escalation hook in the background, logging how it went
+fn run_hook(hook: &[String], device: &device::Info, elapsed: Duration) {
+    let Some((program, args)) = hook.split_first() else {
+        return;
+    };
+
+    let mut command = std::process::Command::new(program);
+    command
+        .args(args)
+        .env("U2F_TD_ELAPSED", elapsed.as_secs().to_string());
+    let job = exec::Job::new("notify", "escalation-hook", device, command);
+    // run on a separate thread so a slow hook doesn't block notifications
+    std::thread::spawn(move || job.run(COMMAND_TIMEOUT));
+}
+
+/// Forward action and close signals from the notification server, until the connection closes
+#[culpa::try_fn]
+pub(crate) fn listen_signals(
+    backend: Backend,
+    connection: &zbus::blocking::Connection,
+    tx: &mpsc::Sender<Input>,
+) -> Result<()> {
+    let interface = match backend {
+        Backend::Notifications => "org.freedesktop.Notifications",
+        Backend::Portal => portal::INTERFACE,
+    };
+    let rule = zbus::MatchRule::builder()
+        .msg_type(zbus::message::Type::Signal)
+        .interface(interface)?
+        .build();
+
+    for message in zbus::blocking::MessageIterator::for_match_rule(rule, connection, None)? {
+        let message = message?;
+        let input = match message.header().member().map(|m| m.as_str()) {
+            Some("ActionInvoked") if backend == Backend::Portal => {
+                let (id, key, _parameter): (String, String, Vec<zbus::zvariant::OwnedValue>) =
+                    message.body().deserialize()?;
+                let Some(id) = portal::parse_id(&id) else {
+                    continue;
+                };
+                Input::Action { id, key }
+            }
+            Some("ActionInvoked") => {
+                let (id, key): (u32, String) = message.body().deserialize()?;
+                Input::Action { id, key }
+            }
+            Some("NotificationClosed") => {
+                let (id, _reason): (u32, u32) = message.body().deserialize()?;
+                Input::Closed { id }
             }
-            (true, Entry::Occupied(_)) | (false, Entry::Vacant(_)) => {}
+            _ => continue,
+        };
+        if tx.send(input).is_err() {
+            return;
+        }
+    }
+    bail!("connection closed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jiff::civil::time;
+
+    fn rule(
+        from: Option<jiff::civil::Time>,
+        to: Option<jiff::civil::Time>,
+        devices: Option<&[&str]>,
+    ) -> Rule {
+        Rule {
+            from,
+            to,
+            devices: devices.map(|devices| devices.iter().map(|d| (*d).to_owned()).collect()),
+            do_not_disturb: false,
+            action: RuleAction::Mute,
         }
     }
+
+    #[test]
+    fn sound_repeat_must_be_positive() {
+        type Partial = <SoundConfig as confique::Config>::Partial;
+        let repeat = |value| toml::from_str::<Partial>(&format!("repeat = {value:?}"));
+        assert_eq!(repeat("30s").unwrap().repeat, Some(Duration::from_secs(30)));
+        assert!(repeat("0s").is_err());
+    }
+
+    #[test]
+    fn rule_time_windows() {
+        let info = device::Info::fake("12345678");
+        let matches =
+            |rule: &Rule, hour, minute| rule.matches(&info, None, time(hour, minute, 0, 0));
+
+        let always = rule(None, None, None);
+        assert!(matches(&always, 0, 0));
+        assert!(matches(&always, 23, 59));
+
+        let daytime = rule(Some(time(9, 0, 0, 0)), Some(time(17, 30, 0, 0)), None);
+        assert!(!matches(&daytime, 8, 59));
+        assert!(matches(&daytime, 9, 0));
+        assert!(matches(&daytime, 17, 29));
+        assert!(!matches(&daytime, 17, 30));
+        assert!(!matches(&daytime, 23, 0));
+
+        let overnight = rule(Some(time(22, 0, 0, 0)), Some(time(7, 0, 0, 0)), None);
+        assert!(!matches(&overnight, 21, 59));
+        assert!(matches(&overnight, 22, 0));
+        assert!(matches(&overnight, 23, 59));
+        assert!(matches(&overnight, 0, 0));
+        assert!(matches(&overnight, 6, 59));
+        assert!(!matches(&overnight, 7, 0));
+        assert!(!matches(&overnight, 12, 0));
+
+        let evening = rule(Some(time(18, 0, 0, 0)), None, None);
+        assert!(!matches(&evening, 17, 59));
+        assert!(matches(&evening, 18, 0));
+        assert!(matches(&evening, 23, 59));
+
+        let morning = rule(None, Some(time(8, 0, 0, 0)), None);
+        assert!(matches(&morning, 0, 0));
+        assert!(!matches(&morning, 8, 0));
+    }
+
+    #[test]
+    fn rule_devices() {
+        let info = device::Info::fake("12345678");
+        let noon = time(12, 0, 0, 0);
+
+        let by_serial = rule(None, None, Some(&["12345678"]));
+        assert!(by_serial.matches(&info, None, noon));
+        assert!(by_serial.matches(&info, Some("work"), noon));
+        assert!(!by_serial.matches(&device::Info::fake("87654321"), None, noon));
+
+        let by_alias = rule(None, None, Some(&["work"]));
+        assert!(by_alias.matches(&info, Some("work"), noon));
+        assert!(!by_alias.matches(&info, Some("home"), noon));
+        assert!(!by_alias.matches(&info, None, noon));
+
+        let none = rule(None, None, Some(&[]));
+        assert!(!none.matches(&info, Some("work"), noon));
+
+        // both the window and the device have to match
+        let work_hours = rule(
+            Some(time(9, 0, 0, 0)),
+            Some(time(17, 0, 0, 0)),
+            Some(&["work"]),
+        );
+        assert!(work_hours.matches(&info, Some("work"), noon));
+        assert!(!work_hours.matches(&info, Some("work"), time(20, 0, 0, 0)));
+        assert!(!work_hours.matches(&info, Some("home"), noon));
+    }
 }
@@ -0,0 +1,617 @@
+use eyre::{bail, ensure, Error, OptionExt, Result, WrapErr};
+use serde::Deserialize;
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    sync::mpsc::RecvTimeoutError,
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, warn};
+
+use crate::{event, metrics};
+
+// https://gitlab.com/CalcProgrammer1/OpenRGB/-/blob/master/Documentation/OpenRGBSDK.md
+const MAGIC: &[u8; 4] = b"ORGB";
+/// Version 2 added profiles, later versions only add fields we don't need
+const PROTOCOL_VERSION: u32 = 2;
+const CLIENT_NAME: &str = "u2f-touch-detector";
+/// Profile the lighting is saved to while we take it over, unless `profile` is configured
+const SAVED_PROFILE: &str = "u2f-touch-detector-saved";
+const IO_TIMEOUT: Duration = Duration::from_secs(2);
+
+const REQUEST_CONTROLLER_COUNT: u32 = 0;
+const REQUEST_CONTROLLER_DATA: u32 = 1;
+const REQUEST_PROTOCOL_VERSION: u32 = 40;
+const SET_CLIENT_NAME: u32 = 50;
+const REQUEST_SAVE_PROFILE: u32 = 151;
+const REQUEST_LOAD_PROFILE: u32 = 152;
+const REQUEST_DELETE_PROFILE: u32 = 153;
+const RGBCONTROLLER_UPDATELEDS: u32 = 1050;
+const RGBCONTROLLER_UPDATEZONELEDS: u32 = 1051;
+const RGBCONTROLLER_SETCUSTOMMODE: u32 = 1100;
+
+#[derive(confique::Config, Debug)]
+#[config(partial_attr(derive(Clone, Debug)))]
+#[config(partial_attr(serde(deny_unknown_fields, rename_all = "kebab-case")))]
+pub struct Config {
+    /// Enable module
+    #[config(default = false)]
+    pub enable: bool,
+
+    /// Address of the OpenRGB SDK server, it is connected to each time a touch is needed so it
+    /// doesn't need to be running at startup
+    #[config(default = "127.0.0.1:6742")]
+    address: String,
+
+    /// Names of the devices to light up, as shown in OpenRGB, by default all devices
+    #[config(default = [])]
+    devices: Vec<String>,
+
+    /// Names of the zones to light up on those devices, by default all zones
+    #[config(default = [])]
+    zones: Vec<String>,
+
+    /// Colour to light up as `#rrggbb`
+    #[config(default = "#ff8000")]
+    color: Color,
+
+    /// Either "static" to stay lit while a touch is needed, or "blink"
+    #[config(default = "blink")]
+    effect: Effect,
+
+    /// How long the lighting is lit each blink
+    #[config(default = "250ms", deserialize_with = crate::config::duration)]
+    on: Duration,
+
+    /// How long the lighting is dark between blinks
+    #[config(default = "250ms", deserialize_with = crate::config::duration)]
+    off: Duration,
+
+    /// Profile to load afterwards, by default the lighting is saved to a temporary profile
+    /// beforehand and restored from that
+    profile: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Effect {
+    Static,
+    Blink,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct Color([u8; 3]);
+
+impl TryFrom<String> for Color {
+    type Error = Error;
+
+    #[culpa::try_fn]
+    fn try_from(value: String) -> Result<Self> {
+        let hex = value
+            .strip_prefix('#')
+            .ok_or_eyre("colour must be formatted as #rrggbb")?;
+        let mut color = [0; 3];
+        hex::decode_to_slice(hex, &mut color)
+            .wrap_err_with(|| format!("invalid colour {value:?}, must be formatted as #rrggbb"))?;
+        Self(color)
+    }
+}
+
+/// The parts of a controller we need to set its colours
+#[derive(Debug)]
+struct Controller {
+    index: u32,
+    name: String,
+    /// (name, number of LEDs) in order
+    zones: Vec<(String, u32)>,
+    leds: u16,
+}
+
+/// A connection to the server while we have taken over the lighting
+struct Session {
+    stream: TcpStream,
+    controllers: Vec<Controller>,
+}
+
+#[culpa::try_fn]
+pub(crate) fn run(config: Config, rx: event::Receiver) -> Result<()> {
+    let changes = event::relay_pending(rx);
+
+    let mut session = None;
+    let mut lit = false;
+    let mut next_toggle: Option<Instant> = None;
+    loop {
+        let change = match next_toggle {
+            Some(at) => changes.recv_timeout(at.saturating_duration_since(Instant::now())),
+            None => changes.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        match change {
+            Ok(true) => {
+                debug!("touch pending, lighting up");
+                match Session::start(&config) {
+                    Ok(started) => {
+                        session = Some(started);
+                        lit = false;
+                        next_toggle = Some(Instant::now());
+                    }
+                    Err(err) => fail(&err),
+                }
+            }
+            Ok(false) => {
+                debug!("no touch pending, restoring lighting");
+                next_toggle = None;
+                if let Some(session) = session.take() {
+                    if let Err(err) = session.restore(&config) {
+                        fail(&err);
+                    }
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                let Some(current) = &mut session else {
+                    next_toggle = None;
+                    continue;
+                };
+                lit = !lit;
+                let color = if lit { config.color } else { Color([0; 3]) };
+                if let Err(err) = current.set(&config, color) {
+                    // probably lost the server, there's nothing left to restore
+                    fail(&err);
+                    session = None;
+                    next_toggle = None;
+                    continue;
+                }
+                next_toggle = match (config.effect, lit) {
+                    (Effect::Static, _) => None,
+                    (Effect::Blink, true) => Some(Instant::now() + config.on),
+                    (Effect::Blink, false) => Some(Instant::now() + config.off),
+                };
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                if let Some(session) = session.take() {
+                    if let Err(err) = session.restore(&config) {
+                        fail(&err);
+                    }
+                }
+                break;
+            }
+        }
+    }
+}
+
+fn fail(err: &Error) {
+    metrics::OUTPUT_FAILURES.increment(&["openrgb"]);
+    warn!("failed to drive openrgb: {err:?}");
+}
+
+impl Session {
+    /// Connect, find the controllers to drive, save the current lighting and take control
+    #[culpa::try_fn]
+    fn start(config: &Config) -> Result<Self> {
+        let stream = TcpStream::connect(&config.address)
+            .wrap_err_with(|| format!("cannot connect to openrgb at {}", config.address))?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        let mut session = Self {
+            stream,
+            controllers: Vec::new(),
+        };
+
+        session.send(0, REQUEST_PROTOCOL_VERSION, &PROTOCOL_VERSION.to_le_bytes())?;
+        let version = session.receive(0, REQUEST_PROTOCOL_VERSION)?;
+        let version = Reader(&version).u32()?;
+        ensure!(
+            version >= PROTOCOL_VERSION,
+            "openrgb server protocol version {version} is too old, profiles need version {PROTOCOL_VERSION}",
+        );
+
+        session.send(0, SET_CLIENT_NAME, &nul_terminated(CLIENT_NAME))?;
+
+        session.send(0, REQUEST_CONTROLLER_COUNT, &[])?;
+        let count = Reader(&session.receive(0, REQUEST_CONTROLLER_COUNT)?).u32()?;
+        for index in 0..count {
+            session.send(
+                index,
+                REQUEST_CONTROLLER_DATA,
+                &PROTOCOL_VERSION.to_le_bytes(),
+            )?;
+            let data = session.receive(index, REQUEST_CONTROLLER_DATA)?;
+            let controller = Controller::parse(index, &data)
+                .wrap_err_with(|| format!("invalid data for controller {index}"))?;
+            debug!(?controller, "found openrgb controller");
+            if config.devices.is_empty() || config.devices.contains(&controller.name) {
+                session.controllers.push(controller);
+            }
+        }
+        for name in &config.devices {
+            if !session.controllers.iter().any(|c| &c.name == name) {
+                warn!(device = name, "openrgb device not found");
+            }
+        }
+
+        if config.profile.is_none() {
+            session.send(0, REQUEST_SAVE_PROFILE, &nul_terminated(SAVED_PROFILE))?;
+        }
+
+        for index in session
+            .controllers
+            .iter()
+            .map(|c| c.index)
+            .collect::<Vec<_>>()
+        {
+            session.send(index, RGBCONTROLLER_SETCUSTOMMODE, &[])?;
+        }
+
+        info!(
+            devices = session.controllers.len(),
+            "took over openrgb lighting"
+        );
+        session
+    }
+
+    /// Set the configured zones of every controller to `color`
+    #[culpa::try_fn]
+    fn set(&mut self, config: &Config, color: Color) -> Result<()> {
+        let Color([r, g, b]) = color;
+        let mut packets = Vec::new();
+        for controller in &self.controllers {
+            if config.zones.is_empty() {
+                let mut data = Vec::new();
+                data.extend_from_slice(&controller.leds.to_le_bytes());
+                for _ in 0..controller.leds {
+                    data.extend_from_slice(&[r, g, b, 0]);
+                }
+                packets.push((controller.index, RGBCONTROLLER_UPDATELEDS, sized(data)));
+                continue;
+            }
+            for (zone, (name, leds)) in controller.zones.iter().enumerate() {
+                if !config.zones.contains(name) {
+                    continue;
+                }
+                let mut data = Vec::new();
+                data.extend_from_slice(&(zone as u32).to_le_bytes());
+                data.extend_from_slice(&(*leds as u16).to_le_bytes());
+                for _ in 0..*leds {
+                    data.extend_from_slice(&[r, g, b, 0]);
+                }
+                packets.push((controller.index, RGBCONTROLLER_UPDATEZONELEDS, sized(data)));
+            }
+        }
+        for (index, id, data) in packets {
+            self.send(index, id, &data)?;
+        }
+    }
+
+    /// Put the lighting back how it was
+    #[culpa::try_fn]
+    fn restore(mut self, config: &Config) -> Result<()> {
+        match &config.profile {
+            Some(profile) => self.send(0, REQUEST_LOAD_PROFILE, &nul_terminated(profile))?,
+            None => {
+                self.send(0, REQUEST_LOAD_PROFILE, &nul_terminated(SAVED_PROFILE))?;
+                self.send(0, REQUEST_DELETE_PROFILE, &nul_terminated(SAVED_PROFILE))?;
+            }
+        }
+        info!("restored openrgb lighting");
+    }
+
+    #[culpa::try_fn]
+    fn send(&mut self, device: u32, id: u32, data: &[u8]) -> Result<()> {
+        let mut packet = Vec::with_capacity(16 + data.len());
+        packet.extend_from_slice(MAGIC);
+        packet.extend_from_slice(&device.to_le_bytes());
+        packet.extend_from_slice(&id.to_le_bytes());
+        packet.extend_from_slice(&u32::try_from(data.len())?.to_le_bytes());
+        packet.extend_from_slice(data);
+        self.stream.write_all(&packet)?;
+    }
+
+    /// Read the response to a request, skipping any unrelated notifications from the server
+    #[culpa::try_fn]
+    fn receive(&mut self, device: u32, id: u32) -> Result<Vec<u8>> {
+        loop {
+            let mut header = [0; 16];
+            self.stream.read_exact(&mut header)?;
+            let field = |i: usize| u32::from_le_bytes(header[i..][..4].try_into().unwrap());
+            ensure!(&header[..4] == MAGIC, "invalid packet magic from openrgb");
+            let mut data = vec![0; usize::try_from(field(12))?];
+            self.stream.read_exact(&mut data)?;
+            if (field(4), field(8)) == (device, id) {
+                break data;
+            }
+            debug!(device = field(4), id = field(8), "skipping openrgb packet");
+        }
+    }
+}
+
+impl Controller {
+    /// Parse the controller data blob, as sent for protocol version 2
+    #[culpa::try_fn]
+    fn parse(index: u32, data: &[u8]) -> Result<Self> {
+        let mut reader = Reader(data);
+        let _size = reader.u32()?;
+        let _kind = reader.u32()?;
+        let name = reader.string()?;
+        // vendor, description, version, serial, location
+        for _ in 0..5 {
+            reader.string()?;
+        }
+
+        let modes = reader.u16()?;
+        let _active_mode = reader.u32()?;
+        for _ in 0..modes {
+            reader.string()?;
+            // value, flags, speed min/max, colors min/max, speed, direction, color mode
+            reader.skip(9 * 4)?;
+            let colors = reader.u16()?;
+            reader.skip(usize::from(colors) * 4)?;
+        }
+
+        let mut zones = Vec::new();
+        for _ in 0..reader.u16()? {
+            let name = reader.string()?;
+            // type, leds min/max
+            reader.skip(3 * 4)?;
+            let leds = reader.u32()?;
+            let matrix = reader.u16()?;
+            reader.skip(usize::from(matrix))?;
+            zones.push((name, leds));
+        }
+
+        let leds = reader.u16()?;
+
+        Self {
+            index,
+            name,
+            zones,
+            leds,
+        }
+    }
+}
+
+/// Little endian cursor over a packet
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    #[culpa::try_fn]
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        if self.0.len() < len {
+            bail!("packet truncated");
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        taken
+    }
+
+    #[culpa::try_fn]
+    fn skip(&mut self, len: usize) -> Result<()> {
+        self.take(len)?;
+    }
+
+    #[culpa::try_fn]
+    fn u16(&mut self) -> Result<u16> {
+        u16::from_le_bytes(self.take(2)?.try_into()?)
+    }
+
+    #[culpa::try_fn]
+    fn u32(&mut self) -> Result<u32> {
+        u32::from_le_bytes(self.take(4)?.try_into()?)
+    }
+
+    /// A length prefixed string, the length includes the trailing nul
+    #[culpa::try_fn]
+    fn string(&mut self) -> Result<String> {
+        let len = usize::from(self.u16()?);
+        let bytes = self.take(len)?;
+        let bytes = bytes.strip_suffix(&[0]).unwrap_or(bytes);
+        String::from_utf8_lossy(bytes).into_owned()
+    }
+}
+
+fn nul_terminated(value: &str) -> Vec<u8> {
+    let mut bytes = value.as_bytes().to_vec();
+    bytes.push(0);
+    bytes
+}
+
+/// Prefix data with its total size including the prefix, as the update packets expect
+fn sized(data: Vec<u8>) -> Vec<u8> {
+    let size = u32::try_from(data.len() + 4).unwrap_or(u32::MAX);
+    let mut sized = size.to_le_bytes().to_vec();
+    sized.extend(data);
+    sized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::testing::{self, touch_finished, touch_needed};
+    use std::{net::TcpListener, sync::mpsc};
+
+    fn string(data: &mut Vec<u8>, value: &str) {
+        data.extend_from_slice(&(value.len() as u16 + 1).to_le_bytes());
+        data.extend_from_slice(&nul_terminated(value));
+    }
+
+    /// Controller data as a version 2 server sends it, with a mode that has colours, and a zone
+    /// with a matrix map
+    fn controller_data(name: &str) -> Vec<u8> {
+        let mut data = Vec::new();
+        // type
+        data.extend_from_slice(&0u32.to_le_bytes());
+        for value in [name, "vendor", "description", "1.0", "serial", "location"] {
+            string(&mut data, value);
+        }
+
+        data.extend_from_slice(&2u16.to_le_bytes());
+        // active mode
+        data.extend_from_slice(&1u32.to_le_bytes());
+        for (mode, colors) in [("Direct", 0u16), ("Static", 2)] {
+            string(&mut data, mode);
+            data.extend_from_slice(&[0; 9 * 4]);
+            data.extend_from_slice(&colors.to_le_bytes());
+            data.resize(data.len() + usize::from(colors) * 4, 0xff);
+        }
+
+        data.extend_from_slice(&2u16.to_le_bytes());
+        string(&mut data, "Keyboard");
+        data.extend_from_slice(&[0; 3 * 4]);
+        data.extend_from_slice(&4u32.to_le_bytes());
+        // height and width then a 2x2 map
+        let matrix: Vec<u8> = [2u32, 2, 0, 1, 2, 3]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        data.extend_from_slice(&(matrix.len() as u16).to_le_bytes());
+        data.extend(matrix);
+        string(&mut data, "Logo");
+        data.extend_from_slice(&[0; 3 * 4]);
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&0u16.to_le_bytes());
+
+        data.extend_from_slice(&5u16.to_le_bytes());
+        // the LED names and colours follow, which we don't read
+        string(&mut data, "Key: A");
+        sized(data)
+    }
+
+    #[test]
+    fn parse_controller() {
+        let controller = Controller::parse(3, &controller_data("Keyboard RGB")).unwrap();
+        assert_eq!(controller.index, 3);
+        assert_eq!(controller.name, "Keyboard RGB");
+        assert_eq!(
+            controller.zones,
+            [("Keyboard".to_owned(), 4), ("Logo".to_owned(), 1)],
+        );
+        assert_eq!(controller.leds, 5);
+
+        let data = controller_data("Keyboard RGB");
+        assert!(Controller::parse(0, &data[..data.len() - 20]).is_err());
+    }
+
+    /// Answer requests like an OpenRGB server with one controller, sending each packet received
+    fn serve(listener: TcpListener, packets: mpsc::Sender<(u32, u32, Vec<u8>)>) {
+        let (mut stream, _) = listener.accept().unwrap();
+        loop {
+            let mut header = [0; 16];
+            if stream.read_exact(&mut header).is_err() {
+                break;
+            }
+            assert_eq!(&header[..4], MAGIC);
+            let field = |i: usize| u32::from_le_bytes(header[i..][..4].try_into().unwrap());
+            let (device, id) = (field(4), field(8));
+            let mut data = vec![0; field(12) as usize];
+            stream.read_exact(&mut data).unwrap();
+
+            let reply = match id {
+                REQUEST_PROTOCOL_VERSION => Some(3u32.to_le_bytes().to_vec()),
+                REQUEST_CONTROLLER_COUNT => Some(1u32.to_le_bytes().to_vec()),
+                REQUEST_CONTROLLER_DATA => Some(controller_data("Keyboard RGB")),
+                _ => None,
+            };
+            if let Some(reply) = reply {
+                let mut packet = MAGIC.to_vec();
+                packet.extend_from_slice(&device.to_le_bytes());
+                packet.extend_from_slice(&id.to_le_bytes());
+                packet.extend_from_slice(&(reply.len() as u32).to_le_bytes());
+                packet.extend(reply);
+                stream.write_all(&packet).unwrap();
+            }
+            packets.send((device, id, data)).unwrap();
+        }
+    }
+
+    /// Config for a fake server, with the packets it receives
+    fn fake_server() -> (Config, mpsc::Receiver<(u32, u32, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (packets_tx, packets) = mpsc::channel();
+        std::thread::spawn(move || serve(listener, packets_tx));
+
+        let config = Config {
+            enable: true,
+            address,
+            devices: Vec::new(),
+            zones: vec!["Logo".to_owned()],
+            color: Color([0x12, 0x34, 0x56]),
+            effect: Effect::Static,
+            on: Duration::from_millis(250),
+            off: Duration::from_millis(250),
+            profile: None,
+        };
+        (config, packets)
+    }
+
+    #[test]
+    fn takes_over_and_restores() {
+        let (config, packets) = fake_server();
+        let (events, _output) = testing::spawn(move |rx| run(config, rx));
+        let device = testing::device();
+        events.send(touch_needed(&device)).unwrap();
+        let next = || packets.recv_timeout(Duration::from_secs(1)).unwrap();
+
+        assert_eq!(
+            next(),
+            (
+                0,
+                REQUEST_PROTOCOL_VERSION,
+                PROTOCOL_VERSION.to_le_bytes().to_vec()
+            ),
+        );
+        assert_eq!(
+            next(),
+            (0, SET_CLIENT_NAME, nul_terminated("u2f-touch-detector"))
+        );
+        assert_eq!(next(), (0, REQUEST_CONTROLLER_COUNT, Vec::new()));
+        assert_eq!(next().1, REQUEST_CONTROLLER_DATA);
+        assert_eq!(
+            next(),
+            (0, REQUEST_SAVE_PROFILE, nul_terminated(SAVED_PROFILE))
+        );
+        assert_eq!(next(), (0, RGBCONTROLLER_SETCUSTOMMODE, Vec::new()));
+        // size, zone 1 with 1 LED, then the colour
+        assert_eq!(
+            next(),
+            (
+                0,
+                RGBCONTROLLER_UPDATEZONELEDS,
+                vec![14, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0x12, 0x34, 0x56, 0],
+            ),
+        );
+
+        events.send(touch_finished(&device)).unwrap();
+        assert_eq!(
+            next(),
+            (0, REQUEST_LOAD_PROFILE, nul_terminated(SAVED_PROFILE))
+        );
+        assert_eq!(
+            next(),
+            (0, REQUEST_DELETE_PROFILE, nul_terminated(SAVED_PROFILE))
+        );
+        assert!(packets.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn restores_profile_when_stopped() {
+        let (config, packets) = fake_server();
+        let (events, output) = testing::spawn(move |rx| run(config, rx));
+        events.send(touch_needed(&testing::device())).unwrap();
+        let next = || packets.recv_timeout(Duration::from_secs(1)).unwrap();
+        while next().1 != RGBCONTROLLER_UPDATEZONELEDS {}
+
+        // the pending relay disconnects on shutdown as it does once there are no more events, so
+        // the output loads the saved profile and stops while the touch is still pending
+        drop(events);
+        output.join().unwrap().unwrap();
+        assert_eq!(
+            next(),
+            (0, REQUEST_LOAD_PROFILE, nul_terminated(SAVED_PROFILE))
+        );
+        assert_eq!(
+            next(),
+            (0, REQUEST_DELETE_PROFILE, nul_terminated(SAVED_PROFILE))
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use eyre::{OptionExt, Result, WrapErr};
+use eyre::{ensure, OptionExt, Result, WrapErr};
 use std::time::Instant;
 use zerocopy::{AsBytes, FromBytes, FromZeroes, BE, U16};
 
@@ -11,13 +11,18 @@ pub(crate) const FIDO_CTAPHID_MAX_RECORD_SIZE: usize = 64;
 #[repr(transparent)]
 pub(crate) struct Channel(pub [u8; 4]);
 
+impl Channel {
+    // https://fidoalliance.org/specs/fido-v2.0-ps-20190130/fido-client-to-authenticator-protocol-v2.0-ps-20190130.html#usb-channels
+    pub(crate) const BROADCAST: Self = Self([0xff; 4]);
+}
+
 impl std::fmt::Debug for Channel {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", hex::encode(self.0))
     }
 }
 
-#[derive(FromZeroes, FromBytes)]
+#[derive(FromZeroes, FromBytes, AsBytes)]
 #[repr(C)]
 pub(crate) struct Init {
     pub(crate) channel: Channel,
@@ -27,6 +32,36 @@ pub(crate) struct Init {
     pub(crate) payload: [u8; FIDO_CTAPHID_MAX_RECORD_SIZE - 7],
 }
 
+impl Init {
+    /// Create a message that fits entirely within a single init packet
+    #[culpa::try_fn]
+    pub(crate) fn new(channel: Channel, command: command::Kind, payload: &[u8]) -> Result<Self> {
+        let mut init = Self {
+            channel,
+            command,
+            length: U16::new(0),
+            payload: [0; FIDO_CTAPHID_MAX_RECORD_SIZE - 7],
+        };
+        ensure!(
+            payload.len() <= init.payload.len(),
+            "payload too long for a single packet (length {} > max {})",
+            payload.len(),
+            init.payload.len(),
+        );
+        init.length = U16::new(payload.len() as u16);
+        init.payload[..payload.len()].copy_from_slice(payload);
+        init
+    }
+
+    #[culpa::try_fn]
+    pub(crate) fn write_to(&self, device: &hidapi::HidDevice) -> Result<()> {
+        // hidapi expects a leading report id, CTAPHID doesn't use them so it's always 0
+        let mut report = [0; FIDO_CTAPHID_MAX_RECORD_SIZE + 1];
+        report[1..].copy_from_slice(self.as_bytes());
+        device.write(&report)?;
+    }
+}
+
 impl std::fmt::Debug for Init {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Init")
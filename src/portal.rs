@@ -0,0 +1,285 @@
+use eyre::Result;
+use notify_rust::{Hint, Notification, Urgency};
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use tracing::{info, warn};
+use zbus::zvariant::Value;
+
+pub(crate) const INTERFACE: &str = "org.freedesktop.portal.Notification";
+
+/// Prefix for the ids of notifications sent to the portal, followed by a unique number
+const ID_PREFIX: &str = "u2f-touch-";
+
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+/// Connection to the XDG desktop portal, which forwards notifications to the desktop from inside
+/// sandboxes
+#[derive(Debug, Clone)]
+pub(crate) struct Portal {
+    proxy: zbus::blocking::Proxy<'static>,
+}
+
+impl Portal {
+    #[culpa::try_fn]
+    pub(crate) fn new(connection: &zbus::blocking::Connection) -> Result<Self> {
+        let proxy = zbus::blocking::Proxy::new(
+            connection,
+            "org.freedesktop.portal.Desktop",
+            "/org/freedesktop/portal/desktop",
+            INTERFACE,
+        )?;
+        let version: u32 = proxy.get_property("version")?;
+        info!(version, "found notification portal");
+        Self { proxy }
+    }
+
+    #[culpa::try_fn]
+    pub(crate) fn show(&self, notification: Notification) -> Result<Handle> {
+        let handle = Handle {
+            portal: self.clone(),
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            notification,
+        };
+        handle.add()?;
+        handle
+    }
+}
+
+/// A notification shown through the portal, mirroring `notify_rust::NotificationHandle`
+#[derive(Debug)]
+pub(crate) struct Handle {
+    portal: Portal,
+    id: u32,
+    notification: Notification,
+}
+
+impl Handle {
+    pub(crate) fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Send `notification` in place of the shown one, the portal replaces notifications with the
+    /// same id
+    pub(crate) fn replace(&mut self, notification: Notification) {
+        self.notification = notification;
+        if let Err(err) = self.add() {
+            warn!("failed to update portal notification: {err:?}");
+        }
+    }
+
+    pub(crate) fn close(self) {
+        let id = format!("{ID_PREFIX}{}", self.id);
+        if let Err(err) = self.portal.proxy.call_method("RemoveNotification", &(id,)) {
+            warn!("failed to remove portal notification: {err:?}");
+        }
+    }
+
+    #[culpa::try_fn]
+    fn add(&self) -> Result<()> {
+        let id = format!("{ID_PREFIX}{}", self.id);
+        let notification = convert(&self.notification);
+        self.portal
+            .proxy
+            .call_method("AddNotification", &(id, notification))?;
+    }
+}
+
+/// Our id for a notification from the id used with the portal
+pub(crate) fn parse_id(id: &str) -> Option<u32> {
+    id.strip_prefix(ID_PREFIX)?.parse().ok()
+}
+
+/// Translate to the portal's notification format, only the title, body, priority, image and
+/// actions are supported
+fn convert(notification: &Notification) -> HashMap<&'static str, Value<'static>> {
+    let mut converted = HashMap::new();
+    converted.insert("title", Value::from(notification.summary.clone()));
+    converted.insert("body", Value::from(notification.body.clone()));
+
+    for hint in &notification.hints {
+        match hint {
+            Hint::Urgency(urgency) => {
+                let priority = match urgency {
+                    Urgency::Low => "low",
+                    Urgency::Normal => "normal",
+                    Urgency::Critical => "urgent",
+                };
+                converted.insert("priority", Value::from(priority));
+            }
+            // sandboxed apps can't share paths, so send the image contents as a serialized GIcon
+            Hint::ImagePath(path) => match std::fs::read(path) {
+                Ok(bytes) => {
+                    let icon = Value::from(("bytes", Value::from(bytes)));
+                    converted.insert("icon", icon);
+                }
+                Err(err) => warn!(path, "cannot read notification image: {err}"),
+            },
+            _ => {}
+        }
+    }
+
+    let mut buttons = Vec::new();
+    for action in notification.actions.chunks_exact(2) {
+        let [key, label] = action else { unreachable!() };
+        if key == "default" {
+            converted.insert("default-action", Value::from(key.clone()));
+        } else {
+            buttons.push(HashMap::from([
+                ("label", Value::from(label.clone())),
+                ("action", Value::from(key.clone())),
+            ]));
+        }
+    }
+    if !buttons.is_empty() {
+        converted.insert("buttons", Value::from(buttons));
+    }
+
+    converted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use zbus::zvariant::OwnedValue;
+
+    /// Method, notification id and notification of a call to the stub
+    type Call = (String, String, HashMap<String, OwnedValue>);
+
+    /// Records the calls made to it, in place of the desktop's portal
+    #[derive(Default, Clone)]
+    struct Stub {
+        calls: Arc<Mutex<Vec<Call>>>,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.portal.Notification")]
+    impl Stub {
+        fn add_notification(&self, id: String, notification: HashMap<String, OwnedValue>) {
+            let call = ("add".to_owned(), id, notification);
+            self.calls.lock().unwrap().push(call);
+        }
+
+        fn remove_notification(&self, id: String) {
+            let call = ("remove".to_owned(), id, HashMap::new());
+            self.calls.lock().unwrap().push(call);
+        }
+
+        #[zbus(property, name = "version")]
+        fn version(&self) -> u32 {
+            2
+        }
+    }
+
+    /// A portal talking to `stub` over a private connection rather than the session bus
+    fn connect(stub: Stub) -> (Portal, zbus::blocking::Connection) {
+        let (client, server) = std::os::unix::net::UnixStream::pair().unwrap();
+        let server = std::thread::spawn(move || {
+            zbus::blocking::connection::Builder::unix_stream(server)
+                .server(zbus::Guid::generate())
+                .unwrap()
+                .p2p()
+                .serve_at("/org/freedesktop/portal/desktop", stub)
+                .unwrap()
+                .build()
+                .unwrap()
+        });
+        let client = zbus::blocking::connection::Builder::unix_stream(client)
+            .p2p()
+            .build()
+            .unwrap();
+        let server = server.join().unwrap();
+        (Portal::new(&client).unwrap(), server)
+    }
+
+    fn string(value: &OwnedValue) -> String {
+        String::try_from(value.try_clone().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn show_replace_close() {
+        let stub = Stub::default();
+        let (portal, _server) = connect(stub.clone());
+
+        let mut notification = Notification::new();
+        notification
+            .summary("U2F Touch Required")
+            .body("Device 12345678")
+            .urgency(Urgency::Critical)
+            .action("default", "Dismiss")
+            .action("snooze", "Snooze");
+        let mut handle = portal.show(notification).unwrap();
+        let id = format!("{ID_PREFIX}{}", handle.id());
+        assert_eq!(parse_id(&id), Some(handle.id()));
+
+        let mut replacement = Notification::new();
+        replacement
+            .summary("U2F Request touched")
+            .body("Device 12345678, after 3s");
+        handle.replace(replacement);
+        handle.close();
+
+        let calls = stub.calls.lock().unwrap();
+        let kinds: Vec<_> = calls
+            .iter()
+            .map(|(kind, call_id, _)| (kind.as_str(), call_id == &id))
+            .collect();
+        assert_eq!(kinds, [("add", true), ("add", true), ("remove", true)]);
+
+        let shown = &calls[0].2;
+        assert_eq!(string(&shown["title"]), "U2F Touch Required");
+        assert_eq!(string(&shown["body"]), "Device 12345678");
+        assert_eq!(string(&shown["priority"]), "urgent");
+        assert_eq!(string(&shown["default-action"]), "default");
+        let buttons =
+            <Vec<HashMap<String, OwnedValue>>>::try_from(shown["buttons"].try_clone().unwrap())
+                .unwrap();
+        assert_eq!(buttons.len(), 1);
+        assert_eq!(string(&buttons[0]["label"]), "Snooze");
+        assert_eq!(string(&buttons[0]["action"]), "snooze");
+
+        let replaced = &calls[1].2;
+        assert_eq!(string(&replaced["title"]), "U2F Request touched");
+        assert!(!replaced.contains_key("buttons"));
+    }
+
+    #[test]
+    fn actions_reach_notify() {
+        let (portal, server) = connect(Stub::default());
+        let handle = portal.show(Notification::new()).unwrap();
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let client = portal.proxy.connection().clone();
+        std::thread::spawn(move || {
+            crate::notify::listen_signals(crate::notify::Backend::Portal, &client, &tx)
+        });
+
+        // the listener may not be subscribed yet, so repeat the signal until it arrives
+        let input = std::iter::repeat_with(|| {
+            for id in [
+                "other-app-1".to_owned(),
+                format!("{ID_PREFIX}{}", handle.id()),
+            ] {
+                let parameter: Vec<OwnedValue> = Vec::new();
+                server
+                    .emit_signal(
+                        None::<()>,
+                        "/org/freedesktop/portal/desktop",
+                        INTERFACE,
+                        "ActionInvoked",
+                        &(id, "snooze", parameter),
+                    )
+                    .unwrap();
+            }
+            rx.recv_timeout(std::time::Duration::from_millis(100))
+        })
+        .take(50)
+        .find_map(Result::ok)
+        .unwrap();
+        assert!(
+            matches!(&input, crate::notify::Input::Action { id, key } if *id == handle.id() && key == "snooze"),
+            "{input:?}"
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use crate::{
+    atomic_file,
+    event::{self, Event, Outcome},
+    http::{self, Request},
+    metrics,
+};
+use camino::Utf8PathBuf;
+use eyre::{ensure, Result};
+use std::{
+    net::{SocketAddr, TcpListener, TcpStream},
+    time::Duration,
+};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info, warn};
+
+#[derive(confique::Config, Debug)]
+#[config(partial_attr(derive(Clone, Debug)))]
+#[config(partial_attr(serde(deny_unknown_fields, rename_all = "kebab-case")))]
+pub struct Config {
+    /// Enable module, at least one of `listen` and `textfile` must be set
+    #[config(default = false)]
+    pub enable: bool,
+
+    /// Address to serve `/metrics` on, must be a loopback address
+    listen: Option<SocketAddr>,
+
+    /// File to periodically write metrics to, e.g. in node_exporter's textfile collector
+    /// directory, the name must end in `.prom` for it to be collected
+    textfile: Option<Utf8PathBuf>,
+
+    /// How often to write the textfile
+    #[config(default = "15s", deserialize_with = crate::config::duration)]
+    interval: Duration,
+}
+
+#[culpa::try_fn]
+pub(crate) fn run(config: Config, mut rx: event::Receiver) -> Result<()> {
+    ensure!(
+        config.listen.is_some() || config.textfile.is_some(),
+        "prometheus output needs at least one of listen or textfile set",
+    );
+
+    if let Some(listen) = config.listen {
+        ensure!(
+            listen.ip().is_loopback(),
+            "prometheus output must listen on a loopback address, not {listen}",
+        );
+        let listener = TcpListener::bind(listen)?;
+        info!(%listen, "serving metrics");
+        std::thread::spawn(move || serve(listener));
+    }
+
+    if let Some(textfile) = config.textfile {
+        info!(%textfile, "writing metrics to textfile");
+        std::thread::spawn(move || loop {
+            // written atomically so the collector never sees a partially written file
+            if let Err(err) = atomic_file::write(&textfile, metrics::render().as_bytes()) {
+                warn!(%textfile, "failed to write metrics: {err:?}");
+            }
+            std::thread::sleep(config.interval);
+        });
+    }
+
+    loop {
+        match rx.blocking_recv() {
+            Ok(event) => record(&event),
+            Err(RecvError::Lagged(count)) => {
+                warn!(count, "missed events, touch metrics will undercount");
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+/// Update the metrics derived from events
+fn record(event: &Event) {
+    let serial = &*event.device().serial;
+    match event {
+        Event::TouchNeeded { .. } => metrics::TOUCHES_REQUESTED.increment(&[serial]),
+        Event::TouchFinished {
+            outcome, duration, ..
+        } => {
+            metrics::TOUCHES_FINISHED.increment(&[serial, outcome.name()]);
+            // timeouts and cancellations would skew how long touching takes
+            if *outcome == Outcome::Touched {
+                metrics::TOUCH_DURATION.observe(&[serial], duration.as_secs_f64());
+            }
+        }
+        Event::DeviceAdded { .. } => {
+            metrics::DEVICES_ADDED.increment();
+        }
+        Event::DeviceRemoved { .. } => {
+            metrics::DEVICES_REMOVED.increment();
+        }
+        Event::SnoozeRequested { .. } => {}
+    }
+}
+
+fn serve(listener: TcpListener) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("failed to accept metrics client: {err:?}");
+                continue;
+            }
+        };
+        std::thread::spawn(move || {
+            if let Err(err) = handle(stream) {
+                debug!("metrics client errored: {err:?}");
+            }
+        });
+    }
+}
+
+#[culpa::try_fn]
+fn handle(mut stream: TcpStream) -> Result<()> {
+    let request = Request::read_from(&mut stream)?;
+    debug!(?request, "metrics request");
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => {
+            let headers = [("Content-Type", "text/plain; version=0.0.4")];
+            let body = metrics::render();
+            http::write_response(&mut stream, "200 OK", &headers, Some(body.as_bytes()))?;
+        }
+        (_, "/metrics") => {
+            let headers = [("Allow", "GET")];
+            http::write_response(&mut stream, "405 Method Not Allowed", &headers, Some(b""))?;
+        }
+        _ => {
+            http::write_response(&mut stream, "404 Not Found", &[], Some(b""))?;
+        }
+    }
+}
@@ -0,0 +1,445 @@
+use eyre::{ensure, OptionExt, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, info_span, warn};
+
+use crate::{
+    device, event, metrics, models,
+    template::{self, Template},
+    timeouts::{self, Timeouts},
+};
+
+#[derive(confique::Config, Debug)]
+#[config(partial_attr(derive(Clone, Debug)))]
+#[config(partial_attr(serde(deny_unknown_fields, rename_all = "kebab-case")))]
+pub struct Config {
+    /// Enable module
+    #[config(default = false)]
+    pub enable: bool,
+
+    /// Either "ntfy" or "gotify"
+    #[config(default = "ntfy")]
+    service: Service,
+
+    /// For ntfy the topic URL, e.g. `https://ntfy.sh/my-topic`, for Gotify the server URL, e.g.
+    /// `https://gotify.example.com`
+    url: Option<String>,
+
+    /// For ntfy an access token, for Gotify an application token
+    token: Option<String>,
+
+    /// How long a device must be waiting for a touch before pushing
+    #[config(default = "10s", deserialize_with = crate::config::duration)]
+    threshold: Duration,
+
+    /// Push title, supports the same placeholders as `message`
+    #[config(default = "U2F Touch Required")]
+    title: Template,
+
+    /// Push message, supports placeholders `{serial}`, `{product}`, `{manufacturer}`, `{vid}`,
+    /// `{pid}`, `{path}`, `{alias}` (same as `{serial}`), `{pending}` (number of devices
+    /// waiting), `{elapsed}` and `{remaining}` (time until the device is expected to give up)
+    #[config(default = "Device {serial} has been waiting {elapsed}")]
+    message: Template,
+
+    /// One of "min", "low", "default", "high" or "urgent", mapped to ntfy's 1-5 or Gotify's
+    /// 0-10 priorities
+    #[config(default = "high")]
+    priority: Priority,
+
+    /// How many times to retry a push that failed because of the network or a server error
+    #[config(default = 3)]
+    retries: u32,
+
+    /// How long to wait before the first retry, doubling for each retry after up to 5 minutes
+    #[config(default = "5s", deserialize_with = crate::config::duration)]
+    retry_delay: Duration,
+
+    /// How long each push may take
+    #[config(default = "10s", deserialize_with = crate::config::duration)]
+    timeout: Duration,
+}
+
+/// The longest to wait between retries, however many there are
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Service {
+    Ntfy,
+    Gotify,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Priority {
+    Min,
+    Low,
+    Default,
+    High,
+    Urgent,
+}
+
+impl Priority {
+    fn ntfy(self) -> u8 {
+        match self {
+            Self::Min => 1,
+            Self::Low => 2,
+            Self::Default => 3,
+            Self::High => 4,
+            Self::Urgent => 5,
+        }
+    }
+
+    fn gotify(self) -> u8 {
+        match self {
+            Self::Min => 0,
+            Self::Low => 2,
+            Self::Default => 5,
+            Self::High => 8,
+            Self::Urgent => 10,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct GotifyMessage<'a> {
+    title: &'a str,
+    message: &'a str,
+    priority: u8,
+}
+
+#[derive(Debug)]
+struct Waiting {
+    device: Arc<device::Info>,
+    since: Instant,
+    pushed: bool,
+    /// Cleared once the touch finishes, stopping any retries of its push
+    pending: Arc<AtomicBool>,
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        self.pending.store(false, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+struct Push {
+    title: String,
+    message: String,
+}
+
+#[culpa::try_fn]
+pub(crate) fn run(config: Config, mut rx: event::Receiver) -> Result<()> {
+    let url = config
+        .url
+        .clone()
+        .ok_or_eyre("push output needs a url configured")?;
+    ensure!(
+        url.starts_with("https://") || url.starts_with("http://"),
+        "push url must be http or https, not {url:?}",
+    );
+    info!(service = ?config.service, url, "pushing touch requests");
+
+    let (tx, changes) = mpsc::channel();
+    std::thread::spawn(move || {
+        while let Some(event) = event::recv(&mut rx) {
+            let Some(needed) = event.needed() else {
+                continue;
+            };
+            if tx.send((event.device().clone(), needed)).is_err() {
+                break;
+            }
+        }
+    });
+
+    let config = Arc::new(config);
+    let agent = ureq::AgentBuilder::new().timeout(config.timeout).build();
+    let timeouts = Timeouts::load();
+    let mut waiting: HashMap<Arc<str>, Waiting> = HashMap::new();
+
+    loop {
+        let next_push = waiting
+            .values()
+            .filter(|entry| !entry.pushed)
+            .map(|entry| entry.since + config.threshold)
+            .min();
+        let change = match next_push {
+            Some(at) => changes.recv_timeout(at.saturating_duration_since(Instant::now())),
+            None => changes.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        match change {
+            Ok((device, true)) => {
+                waiting.entry(device.id()).or_insert_with(|| Waiting {
+                    device,
+                    since: Instant::now(),
+                    pushed: false,
+                    pending: Arc::new(AtomicBool::new(true)),
+                });
+            }
+            Ok((device, false)) => {
+                waiting.remove(&device.id());
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                let pending = waiting.len();
+                for entry in waiting.values_mut() {
+                    let elapsed = entry.since.elapsed();
+                    if entry.pushed || elapsed < config.threshold {
+                        continue;
+                    }
+                    entry.pushed = true;
+
+                    let info = &*entry.device;
+                    let remaining = timeouts
+                        .get(info)
+                        .unwrap_or(timeouts::DEFAULT_TIMEOUT)
+                        .saturating_sub(elapsed);
+                    let context = template::Context {
+                        device: info,
+                        alias: None,
+                        model: models::find(info).map(|model| model.name),
+                        pending,
+                        elapsed,
+                        remaining,
+                        outcome: None,
+                    };
+                    let push = Push {
+                        title: config.title.render(&context),
+                        message: config.message.render(&context),
+                    };
+
+                    let span = info_span!("push", device.serial = %info.serial);
+                    std::thread::spawn({
+                        let config = config.clone();
+                        let agent = agent.clone();
+                        let url = url.clone();
+                        let pending = entry.pending.clone();
+                        move || {
+                            let _guard = span.entered();
+                            send_with_retries(&config, &agent, &url, &push, &pending);
+                        }
+                    });
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+}
+
+/// Send the push, retrying with backoff on failures that might be temporary while the touch is
+/// still `pending`
+fn send_with_retries(
+    config: &Config,
+    agent: &ureq::Agent,
+    url: &str,
+    push: &Push,
+    pending: &AtomicBool,
+) {
+    let mut delay = config.retry_delay;
+    for attempt in 0..=config.retries {
+        if attempt > 0 && !pending.load(Ordering::Relaxed) {
+            debug!(attempt, "touch no longer pending, not retrying push");
+            return;
+        }
+        match send(config, agent, url, push) {
+            Ok(()) => {
+                info!("sent push");
+                return;
+            }
+            Err(err) if attempt < config.retries && retryable(&err) => {
+                debug!(attempt, ?delay, "push failed, retrying: {err:?}");
+                std::thread::sleep(delay);
+                delay = next_delay(delay);
+            }
+            Err(err) => {
+                metrics::OUTPUT_FAILURES.increment(&["push"]);
+                warn!("failed to send push: {err:?}");
+                return;
+            }
+        }
+    }
+}
+
+fn next_delay(delay: Duration) -> Duration {
+    delay.saturating_mul(2).min(MAX_RETRY_DELAY)
+}
+
+/// Network errors, rate limiting and server errors might succeed later, other errors like a bad
+/// token won't
+fn retryable(err: &eyre::Report) -> bool {
+    match err.downcast_ref::<ureq::Error>() {
+        Some(ureq::Error::Status(status, _)) => *status == 429 || *status >= 500,
+        Some(ureq::Error::Transport(_)) => true,
+        None => false,
+    }
+}
+
+#[culpa::try_fn]
+fn send(config: &Config, agent: &ureq::Agent, url: &str, push: &Push) -> Result<()> {
+    let result = match config.service {
+        Service::Ntfy => {
+            // query parameters rather than headers so the title can contain any characters
+            let mut request = agent
+                .post(url)
+                .query("title", &push.title)
+                .query("priority", &config.priority.ntfy().to_string());
+            if let Some(token) = &config.token {
+                request = request.set("Authorization", &format!("Bearer {token}"));
+            }
+            request.send_string(&push.message)
+        }
+        Service::Gotify => {
+            let mut request = agent
+                .post(&format!("{}/message", url.trim_end_matches('/')))
+                .set("Content-Type", "application/json");
+            if let Some(token) = &config.token {
+                request = request.set("X-Gotify-Key", token);
+            }
+            let body = serde_json::to_string(&GotifyMessage {
+                title: &push.title,
+                message: &push.message,
+                priority: config.priority.gotify(),
+            })?;
+            request.send_string(&body)
+        }
+    };
+    let response = result?;
+    debug!(status = response.status(), "push accepted");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+    };
+
+    #[derive(Debug)]
+    struct Request {
+        /// Method and path including the query
+        line: String,
+        headers: HashMap<String, String>,
+        body: String,
+    }
+
+    /// Answer requests with each status in turn, sending each request received before answering it
+    fn serve(listener: TcpListener, statuses: Vec<u16>, requests: mpsc::Sender<Request>) {
+        for status in statuses {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let mut headers = HashMap::new();
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                let Some((name, value)) = header.trim_end().split_once(": ") else {
+                    break;
+                };
+                headers.insert(name.to_ascii_lowercase(), value.to_owned());
+            }
+            let length = headers
+                .get("content-length")
+                .map_or(0, |length| length.parse().unwrap());
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            requests
+                .send(Request {
+                    line: line.trim_end().to_owned(),
+                    headers,
+                    body: String::from_utf8(body).unwrap(),
+                })
+                .unwrap();
+            write!(
+                &stream,
+                "HTTP/1.1 {status} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        }
+    }
+
+    /// Send a push to a stub server answering with `statuses`, returning the requests it received
+    fn push(service: Service, path: &str, statuses: Vec<u16>, pending: bool) -> Vec<Request> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}{path}", listener.local_addr().unwrap());
+        let (tx, requests) = mpsc::channel();
+        std::thread::spawn(move || serve(listener, statuses, tx));
+
+        let config = Config {
+            enable: true,
+            service,
+            url: Some(url.clone()),
+            token: Some("secret".to_owned()),
+            threshold: Duration::from_secs(10),
+            title: Template::try_from("Touch".to_owned()).unwrap(),
+            message: Template::try_from("Device waiting".to_owned()).unwrap(),
+            priority: Priority::Urgent,
+            retries: 3,
+            retry_delay: Duration::from_millis(1),
+            timeout: Duration::from_secs(1),
+        };
+        let agent = ureq::AgentBuilder::new().timeout(config.timeout).build();
+        let push = Push {
+            title: "Touch \"now\"".to_owned(),
+            message: "Device 12345678 waiting".to_owned(),
+        };
+        send_with_retries(&config, &agent, &url, &push, &AtomicBool::new(pending));
+        requests.try_iter().collect()
+    }
+
+    #[test]
+    fn ntfy_retries_server_errors() {
+        let requests = push(Service::Ntfy, "/topic", vec![503, 200], true);
+        assert_eq!(requests.len(), 2);
+        for request in &requests {
+            assert_eq!(
+                request.line,
+                "POST /topic?title=Touch+%22now%22&priority=5 HTTP/1.1",
+            );
+            assert_eq!(request.headers["authorization"], "Bearer secret");
+            assert_eq!(request.body, "Device 12345678 waiting");
+        }
+    }
+
+    #[test]
+    fn gotify_gives_up_on_client_errors() {
+        let requests = push(Service::Gotify, "/", vec![401, 200], true);
+        assert_eq!(requests.len(), 1);
+        let request = &requests[0];
+        assert_eq!(request.line, "POST /message HTTP/1.1");
+        assert_eq!(request.headers["x-gotify-key"], "secret");
+        assert_eq!(request.headers["content-type"], "application/json");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&request.body).unwrap(),
+            serde_json::json!({
+                "title": "Touch \"now\"",
+                "message": "Device 12345678 waiting",
+                "priority": 10,
+            }),
+        );
+    }
+
+    #[test]
+    fn no_retries_once_touched() {
+        let requests = push(Service::Ntfy, "/topic", vec![503, 200], false);
+        assert_eq!(requests.len(), 1);
+    }
+
+    #[test]
+    fn retry_delay_is_capped() {
+        assert_eq!(next_delay(Duration::from_secs(5)), Duration::from_secs(10));
+        assert_eq!(next_delay(Duration::from_secs(200)), MAX_RETRY_DELAY);
+        assert_eq!(next_delay(Duration::MAX), MAX_RETRY_DELAY);
+    }
+}
@@ -0,0 +1,159 @@
+use eyre::{ensure, Result};
+use std::{
+    sync::{Condvar, Mutex},
+    time::Duration,
+};
+use tracing::{info, warn};
+
+/// How long to wait for outputs to put things back before exiting anyway
+const GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// Outputs to stop when the process is asked to terminate
+pub(crate) static OUTPUTS: Registry = Registry::new();
+
+/// Outputs that change something outside the process, e.g. light an LED, and have to put it back
+/// before the process exits
+pub(crate) struct Registry {
+    state: Mutex<State>,
+    finished: Condvar,
+}
+
+struct State {
+    stops: Vec<Box<dyn FnOnce() + Send>>,
+    /// Guards not yet dropped
+    running: usize,
+    stopping: bool,
+}
+
+/// Held by an output until it has put things back, the process waits for every guard to be
+/// dropped before exiting
+#[must_use]
+pub(crate) struct Guard {
+    registry: &'static Registry,
+}
+
+impl Registry {
+    const fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                stops: Vec::new(),
+                running: 0,
+                stopping: false,
+            }),
+            finished: Condvar::new(),
+        }
+    }
+
+    /// Call `stop` on shutdown, it should make the output put things back then drop the guard
+    pub(crate) fn register(&'static self, stop: impl FnOnce() + Send + 'static) -> Guard {
+        let mut state = self.state.lock().unwrap();
+        state.running += 1;
+        if state.stopping {
+            drop(state);
+            stop();
+        } else {
+            state.stops.push(Box::new(stop));
+        }
+        Guard { registry: self }
+    }
+
+    /// Stop every output, returning whether they all finished within `timeout`
+    fn stop(&self, timeout: Duration) -> bool {
+        let stops = {
+            let mut state = self.state.lock().unwrap();
+            state.stopping = true;
+            std::mem::take(&mut state.stops)
+        };
+        for stop in stops {
+            stop();
+        }
+        let state = self.state.lock().unwrap();
+        let (_state, result) = self
+            .finished
+            .wait_timeout_while(state, timeout, |state| state.running > 0)
+            .unwrap();
+        !result.timed_out()
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        self.registry.state.lock().unwrap().running -= 1;
+        self.registry.finished.notify_all();
+    }
+}
+
+/// Handle SIGINT and SIGTERM on a dedicated thread, stopping the registered outputs before
+/// exiting, must be called before spawning any other thread so they all leave the signals blocked
+#[culpa::try_fn]
+pub(crate) fn handle_signals() -> Result<()> {
+    // SAFETY: the set is initialized by sigemptyset before use, and only valid signals are added
+    let signals = unsafe {
+        let mut signals = std::mem::zeroed::<libc::sigset_t>();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        signals
+    };
+    // SAFETY: `signals` is a valid set, and the old mask isn't wanted
+    let err = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut()) };
+    ensure!(
+        err == 0,
+        "failed to block termination signals: {}",
+        std::io::Error::from_raw_os_error(err)
+    );
+
+    std::thread::spawn(move || {
+        let mut signal = 0;
+        // SAFETY: `signals` is a valid set and `signal` is writable, this only fails for invalid
+        // sets
+        unsafe { libc::sigwait(&signals, &mut signal) };
+        info!(signal, "stopping");
+        if !OUTPUTS.stop(GRACE_PERIOD) {
+            warn!("outputs did not finish within {GRACE_PERIOD:?}, exiting anyway");
+        }
+        std::process::exit(0);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    };
+
+    #[test]
+    fn stop_waits_for_outputs() {
+        let registry: &'static Registry = Box::leak(Box::new(Registry::new()));
+        let restored = Arc::new(AtomicBool::new(false));
+
+        let (tx, rx) = mpsc::channel::<()>();
+        let guard = registry.register(move || drop(tx));
+        std::thread::spawn({
+            let restored = restored.clone();
+            move || {
+                // the output runs until its stop is called, then takes a while to put things back
+                let _ = rx.recv();
+                std::thread::sleep(Duration::from_millis(50));
+                restored.store(true, Ordering::SeqCst);
+                drop(guard);
+            }
+        });
+
+        assert!(registry.stop(Duration::from_secs(1)));
+        assert!(restored.load(Ordering::SeqCst));
+
+        // outputs starting during shutdown are stopped straight away
+        let stopped = Arc::new(AtomicBool::new(false));
+        let guard = registry.register({
+            let stopped = stopped.clone();
+            move || stopped.store(true, Ordering::SeqCst)
+        });
+        assert!(stopped.load(Ordering::SeqCst));
+        assert!(!registry.stop(Duration::from_millis(10)));
+        drop(guard);
+        assert!(registry.stop(Duration::from_millis(10)));
+    }
+}
@@ -1,9 +1,12 @@
 use eyre::{OptionExt, Result};
-use std::{collections::HashSet, io::Write, sync::Arc};
+use std::{collections::HashSet, io::Write};
+use tokio::sync::broadcast::error::RecvError;
 use tracing::{info, info_span, warn};
 
+use crate::{event, metrics};
+
 #[culpa::try_fn]
-pub(crate) fn run(mut rx: tokio::sync::broadcast::Receiver<(Arc<str>, bool)>) -> Result<()> {
+pub(crate) fn run(mut rx: event::Receiver) -> Result<()> {
     let (tx, _) = tokio::sync::broadcast::channel(1);
 
     std::thread::spawn({
@@ -11,17 +14,18 @@ pub(crate) fn run(mut rx: tokio::sync::broadcast::Receiver<(Arc<str>, bool)>) ->
         move || {
             let mut active = HashSet::new();
 
-            while let Ok((serial, needed)) = rx.blocking_recv() {
+            while let Some(event) = event::recv(&mut rx) {
+                let Some(needed) = event.needed() else {
+                    continue;
+                };
+                let id = event.device().id();
                 if needed {
                     if active.is_empty() {
                         let _ = tx.send("U2F_1");
                     }
-                    active.insert(serial);
-                } else {
-                    active.remove(&serial);
-                    if active.is_empty() {
-                        let _ = tx.send("U2F_0");
-                    }
+                    active.insert(id);
+                } else if active.remove(&id) && active.is_empty() {
+                    let _ = tx.send("U2F_0");
                 }
             }
         }
@@ -45,17 +49,28 @@ pub(crate) fn run(mut rx: tokio::sync::broadcast::Receiver<(Arc<str>, bool)>) ->
             let mut rx = tx.subscribe();
             move || {
                 let _guard = span.entered();
-                while let Ok(message) = rx.blocking_recv() {
+                metrics::SOCKET_CLIENTS.increment();
+                loop {
+                    let message = match rx.blocking_recv() {
+                        Ok(message) => message,
+                        // only the latest state matters, and that is received next
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => break,
+                    };
                     match stream.write_all(message.as_bytes()) {
                         Ok(()) => (),
                         Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
                             info!("socket client closed");
+                            break;
                         }
                         Err(e) => {
+                            metrics::OUTPUT_FAILURES.increment(&["socket"]);
                             warn!("error writing to socket: {e:?}");
+                            break;
                         }
                     }
                 }
+                metrics::SOCKET_CLIENTS.decrement();
             }
         });
     }
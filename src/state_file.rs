@@ -0,0 +1,197 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use directories::ProjectDirs;
+use eyre::{eyre, OptionExt, Result, WrapErr};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+use tracing::{info, warn};
+
+use crate::{
+    atomic_file, device,
+    event::{self, Event},
+    metrics,
+};
+
+#[derive(confique::Config, Debug)]
+#[config(partial_attr(derive(Clone, Debug)))]
+#[config(partial_attr(serde(deny_unknown_fields, rename_all = "kebab-case")))]
+pub struct Config {
+    /// Enable module
+    #[config(default = false)]
+    pub enable: bool,
+
+    /// Where to write the state, by default `$XDG_RUNTIME_DIR/u2f-touch-detector/state.json`
+    path: Option<Utf8PathBuf>,
+
+    /// A file that exists while any device is waiting, listing their ids (their serial, or for
+    /// devices without one vendor id, product id and hidraw device), by default
+    /// `$XDG_RUNTIME_DIR/u2f-touch-detector/pending`, or `path` with a `.pending` extension when
+    /// that is configured
+    pending_path: Option<Utf8PathBuf>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct State {
+    #[serde(serialize_with = "values")]
+    devices: BTreeMap<Arc<str>, Arc<device::Info>>,
+    pending: BTreeSet<Arc<str>>,
+}
+
+fn values<S: serde::Serializer>(
+    devices: &BTreeMap<Arc<str>, Arc<device::Info>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(devices.values())
+}
+
+#[culpa::try_fn]
+pub(crate) fn run(config: Config, mut rx: event::Receiver) -> Result<()> {
+    let (path, default_pending_path) = match config.path {
+        Some(path) => {
+            let pending_path = path.with_extension("pending");
+            (path, pending_path)
+        }
+        None => {
+            let dir = runtime_dir()?;
+            (dir.join("state.json"), dir.join("pending"))
+        }
+    };
+    let pending_path = config.pending_path.unwrap_or(default_pending_path);
+    for file in [&path, &pending_path] {
+        let dir = file
+            .parent()
+            .ok_or_else(|| eyre!("state file path {file} has no parent"))?;
+        std::fs::create_dir_all(dir).wrap_err_with(|| format!("failed to create {dir}"))?;
+    }
+
+    let mut state = State::default();
+    // replaces anything left over from a previous run
+    write(&path, &pending_path, &state)?;
+    info!(%path, %pending_path, "writing state file");
+
+    while let Some(event) = event::recv(&mut rx) {
+        let device = event.device();
+        let changed = match &event {
+            Event::TouchNeeded { .. } => state.pending.insert(device.id()),
+            Event::TouchFinished { .. } => state.pending.remove(&device.id()),
+            Event::DeviceAdded { .. } => {
+                state.devices.insert(device.id(), device.clone());
+                true
+            }
+            Event::DeviceRemoved { .. } => {
+                state.pending.remove(&device.id());
+                state.devices.remove(&device.id()).is_some()
+            }
+            Event::SnoozeRequested { .. } => false,
+        };
+        if changed {
+            if let Err(err) = write(&path, &pending_path, &state) {
+                metrics::OUTPUT_FAILURES.increment(&["state-file"]);
+                warn!(%path, "failed to write state file: {err:?}");
+            }
+        }
+    }
+}
+
+#[culpa::try_fn]
+fn runtime_dir() -> Result<Utf8PathBuf> {
+    let dirs = ProjectDirs::from("", "", "u2f-touch-detector")
+        .ok_or_eyre("cannot get runtime directory")?;
+    let dir = dirs
+        .runtime_dir()
+        .ok_or_eyre("XDG_RUNTIME_DIR is not set, configure a path instead")?;
+    Utf8PathBuf::try_from(dir.to_owned())?
+}
+
+/// Write the state file, then create or remove the pending flag to match
+#[culpa::try_fn]
+fn write(path: &Utf8Path, pending_path: &Utf8Path, state: &State) -> Result<()> {
+    let mut json = serde_json::to_vec_pretty(state)?;
+    json.push(b'\n');
+    atomic_file::write(path, &json)?;
+
+    if state.pending.is_empty() {
+        match std::fs::remove_file(pending_path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                Err(err).wrap_err_with(|| format!("failed to remove {pending_path}"))?
+            }
+            _ => {}
+        }
+    } else {
+        let ids: String = state.pending.iter().map(|id| format!("{id}\n")).collect();
+        atomic_file::write(pending_path, ids.as_bytes())?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::testing::{self, eventually, touch_finished, touch_needed};
+    use std::{io::Read, os::unix::fs::MetadataExt};
+
+    fn inode(path: &Utf8Path) -> Option<u64> {
+        std::fs::metadata(path).ok().map(|metadata| metadata.ino())
+    }
+
+    #[test]
+    fn tracks_pending() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        let path = dir.join("state.json");
+        let pending_path = dir.join("state.pending");
+        // somebody else's file that happens to share the directory
+        std::fs::write(dir.join("pending"), "unrelated").unwrap();
+
+        let config = Config {
+            enable: true,
+            path: Some(path.clone()),
+            pending_path: None,
+        };
+        let (events, _output) = testing::spawn(move |rx| run(config, rx));
+
+        eventually(|| inode(&path).is_some());
+        let json = || serde_json::from_slice::<serde_json::Value>(&std::fs::read(&path).unwrap());
+        assert_eq!(
+            json().unwrap(),
+            serde_json::json!({ "devices": [], "pending": [] }),
+        );
+
+        let device = testing::device();
+        // kept open so the old file can be checked after it has been replaced
+        let mut before = std::fs::File::open(&path).unwrap();
+        events
+            .send(Event::DeviceAdded {
+                device: device.clone(),
+            })
+            .unwrap();
+        events.send(touch_needed(&device)).unwrap();
+
+        eventually(|| pending_path.exists());
+        assert_eq!(
+            std::fs::read_to_string(&pending_path).unwrap(),
+            "12345678\n"
+        );
+        let state = json().unwrap();
+        assert_eq!(state["devices"][0]["serial"], "12345678");
+        assert_eq!(state["pending"], serde_json::json!(["12345678"]));
+        // replaced by a rename rather than written in place
+        assert_ne!(inode(&path), Some(before.metadata().unwrap().ino()));
+        let mut old = String::new();
+        before.read_to_string(&mut old).unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&old).unwrap(),
+            serde_json::json!({ "devices": [], "pending": [] }),
+        );
+
+        events.send(touch_finished(&device)).unwrap();
+        eventually(|| !pending_path.exists());
+        assert_eq!(json().unwrap()["pending"], serde_json::json!([]));
+        assert_eq!(
+            std::fs::read_to_string(dir.join("pending")).unwrap(),
+            "unrelated",
+        );
+        assert!(!dir.join("state.json.tmp").exists());
+    }
+}
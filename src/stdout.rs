@@ -0,0 +1,119 @@
+use eyre::Result;
+use serde::Serialize;
+use std::{collections::BTreeSet, io::Write};
+
+use crate::{
+    device,
+    event::{self, Event, Outcome},
+};
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub(crate) enum Format {
+    /// One JSON object per line
+    Json,
+    /// One human readable line per event
+    Text,
+}
+
+#[derive(Debug, Serialize)]
+struct Record<'a> {
+    time: jiff::Timestamp,
+    #[serde(flatten)]
+    line: Line<'a>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+enum Line<'a> {
+    TouchNeeded {
+        device: &'a device::Info,
+        pending: usize,
+    },
+    TouchFinished {
+        device: &'a device::Info,
+        outcome: Outcome,
+        /// Seconds the device was waiting
+        duration: f64,
+        pending: usize,
+    },
+    DeviceAdded {
+        device: &'a device::Info,
+    },
+    DeviceRemoved {
+        device: &'a device::Info,
+    },
+}
+
+impl Line<'_> {
+    fn text(&self) -> String {
+        match self {
+            Self::TouchNeeded { device, pending } => {
+                format!("touch-needed {} pending={pending}", device.serial)
+            }
+            Self::TouchFinished {
+                device,
+                outcome,
+                duration,
+                pending,
+            } => format!(
+                "touch-finished {} outcome={} duration={duration:.1}s pending={pending}",
+                device.serial,
+                outcome.name(),
+            ),
+            Self::DeviceAdded { device } => format!("device-added {}", device.serial),
+            Self::DeviceRemoved { device } => format!("device-removed {}", device.serial),
+        }
+    }
+}
+
+#[culpa::try_fn]
+pub(crate) fn run(format: Format, mut rx: event::Receiver) -> Result<()> {
+    let mut pending = BTreeSet::new();
+
+    while let Some(event) = event::recv(&mut rx) {
+        let device = &**event.device();
+        match event.needed() {
+            Some(true) => pending.insert(device.id()),
+            Some(false) => pending.remove(&device.id()),
+            None => false,
+        };
+
+        let line = match &event {
+            Event::TouchNeeded { .. } => Line::TouchNeeded {
+                device,
+                pending: pending.len(),
+            },
+            Event::TouchFinished {
+                outcome, duration, ..
+            } => Line::TouchFinished {
+                device,
+                outcome: *outcome,
+                duration: duration.as_secs_f64(),
+                pending: pending.len(),
+            },
+            Event::DeviceAdded { .. } => Line::DeviceAdded { device },
+            Event::DeviceRemoved { .. } => Line::DeviceRemoved { device },
+            Event::SnoozeRequested { .. } => continue,
+        };
+
+        write(format, line)?;
+    }
+}
+
+#[culpa::try_fn]
+fn write(format: Format, line: Line<'_>) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    match format {
+        Format::Json => {
+            let record = Record {
+                time: jiff::Timestamp::now(),
+                line,
+            };
+            serde_json::to_writer(&mut stdout, &record)?;
+            writeln!(stdout)?;
+        }
+        Format::Text => writeln!(stdout, "{} {}", jiff::Timestamp::now(), line.text())?,
+    }
+    // flush every line so consumers in a pipeline see events as they happen
+    stdout.flush()?;
+}
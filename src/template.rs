@@ -0,0 +1,247 @@
+use eyre::{bail, Error, Result};
+use serde::Deserialize;
+use std::{fmt::Write, time::Duration};
+
+use crate::{device, event::Outcome};
+
+/// Values available to be substituted into a template
+#[derive(Debug)]
+pub(crate) struct Context<'a> {
+    pub(crate) device: &'a device::Info,
+    pub(crate) alias: Option<&'a str>,
+    /// Name of the recognized device model, used for `{product}` in place of the HID product
+    /// string
+    pub(crate) model: Option<&'a str>,
+    pub(crate) pending: usize,
+    /// How long the device has been waiting for a touch
+    pub(crate) elapsed: Duration,
+    /// How long until the device is expected to give up
+    pub(crate) remaining: Duration,
+    /// How the request ended, if it has
+    pub(crate) outcome: Option<Outcome>,
+}
+
+/// A set of placeholders that can be used in a template, and how to render them
+pub(crate) trait Placeholders: Copy + std::fmt::Debug + 'static {
+    type Context<'a>;
+
+    const ALL: &'static [(&'static str, Self)];
+
+    fn render(self, out: &mut String, context: &Self::Context<'_>);
+}
+
+/// Placeholders describing a single device
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Placeholder {
+    Serial,
+    Product,
+    Manufacturer,
+    Vid,
+    Pid,
+    Path,
+    Alias,
+    Pending,
+    Elapsed,
+    Remaining,
+    Outcome,
+}
+
+impl Placeholders for Placeholder {
+    type Context<'a> = Context<'a>;
+
+    const ALL: &'static [(&'static str, Self)] = &[
+        ("serial", Self::Serial),
+        ("product", Self::Product),
+        ("manufacturer", Self::Manufacturer),
+        ("vid", Self::Vid),
+        ("pid", Self::Pid),
+        ("path", Self::Path),
+        ("alias", Self::Alias),
+        ("pending", Self::Pending),
+        ("elapsed", Self::Elapsed),
+        ("remaining", Self::Remaining),
+        ("outcome", Self::Outcome),
+    ];
+
+    fn render(self, out: &mut String, context: &Context<'_>) {
+        let device = context.device;
+        let _ = match self {
+            Self::Serial => write!(out, "{}", device.serial),
+            Self::Product => write!(out, "{}", context.model.unwrap_or(&device.product)),
+            Self::Manufacturer => write!(out, "{}", device.manufacturer),
+            Self::Vid => write!(out, "{:04x}", device.vendor_id),
+            Self::Pid => write!(out, "{:04x}", device.product_id),
+            Self::Path => write!(out, "{}", device.path),
+            Self::Alias => write!(out, "{}", context.alias.unwrap_or(&device.serial)),
+            Self::Pending => write!(out, "{}", context.pending),
+            Self::Elapsed => write!(out, "{}s", context.elapsed.as_secs()),
+            Self::Remaining => write!(out, "{}s", context.remaining.as_secs()),
+            Self::Outcome => match context.outcome {
+                Some(outcome) => write!(out, "{outcome}"),
+                None => Ok(()),
+            },
+        };
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Part<P> {
+    Literal(String),
+    Placeholder(P),
+}
+
+/// A string with `{name}` placeholders, use `{{` and `}}` for literal braces
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String", bound = "P: Placeholders")]
+pub(crate) struct Template<P = Placeholder> {
+    parts: Vec<Part<P>>,
+}
+
+impl<P: Placeholders> Template<P> {
+    pub(crate) fn render(&self, context: &P::Context<'_>) -> String {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => out.push_str(literal),
+                Part::Placeholder(placeholder) => placeholder.render(&mut out, context),
+            }
+        }
+        out
+    }
+}
+
+impl<P: Placeholders> TryFrom<String> for Template<P> {
+    type Error = Error;
+
+    #[culpa::try_fn]
+    fn try_from(source: String) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = source.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let Some((name, rest)) = chars.as_str().split_once('}') else {
+                        bail!("unclosed placeholder in template {source:?}");
+                    };
+                    let Some(&(_, placeholder)) = P::ALL.iter().find(|(known, _)| *known == name)
+                    else {
+                        let known = P::ALL.iter().map(|(known, _)| *known);
+                        bail!(
+                            "unknown placeholder {{{name}}} in template {source:?}, expected one of {}",
+                            known.collect::<Vec<_>>().join(", ")
+                        );
+                    };
+                    chars = rest.chars();
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Placeholder(placeholder));
+                }
+                '}' => bail!("unmatched '}}' in template {source:?}, use '}}}}' for a literal"),
+                c => literal.push(c),
+            }
+        }
+
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Self { parts }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(info: &device::Info) -> Context<'_> {
+        Context {
+            device: info,
+            alias: None,
+            model: None,
+            pending: 2,
+            elapsed: Duration::from_millis(12_500),
+            remaining: Duration::from_secs(17),
+            outcome: None,
+        }
+    }
+
+    fn parse(source: &str) -> Result<Template> {
+        Template::try_from(source.to_owned())
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        let info = device::Info::fake("12345678");
+        let template = parse("{product} {serial} ({vid}:{pid}) at {path}").unwrap();
+        assert_eq!(
+            template.render(&context(&info)),
+            "YubiKey OTP+FIDO+CCID 12345678 (1050:0407) at /dev/hidraw0",
+        );
+
+        let template = parse("{pending} waiting, {elapsed} elapsed, {remaining} left").unwrap();
+        assert_eq!(
+            template.render(&context(&info)),
+            "2 waiting, 12s elapsed, 17s left",
+        );
+    }
+
+    #[test]
+    fn renders_context_overrides() {
+        let info = device::Info::fake("12345678");
+        let context = Context {
+            alias: Some("work key"),
+            model: Some("YubiKey 5 NFC"),
+            outcome: Some(Outcome::TimedOut),
+            ..context(&info)
+        };
+        let template = parse("{alias}: {product} {outcome}").unwrap();
+        assert_eq!(
+            template.render(&context),
+            "work key: YubiKey 5 NFC timed out"
+        );
+
+        let context = Context {
+            outcome: None,
+            ..context
+        };
+        let template = parse("[{outcome}]").unwrap();
+        assert_eq!(template.render(&context), "[]");
+    }
+
+    #[test]
+    fn escaped_braces() {
+        let info = device::Info::fake("12345678");
+        let template = parse("{{serial}} is {{{serial}}}").unwrap();
+        assert_eq!(template.render(&context(&info)), "{serial} is {12345678}");
+    }
+
+    #[test]
+    fn unknown_placeholder() {
+        let err = parse("hello {name}").unwrap_err().to_string();
+        assert!(err.contains("unknown placeholder {name}"), "{err}");
+        assert!(err.contains("serial, product"), "{err}");
+    }
+
+    #[test]
+    fn unclosed_placeholder() {
+        let err = parse("hello {serial").unwrap_err().to_string();
+        assert!(err.contains("unclosed placeholder"), "{err}");
+    }
+
+    #[test]
+    fn unmatched_closing_brace() {
+        let err = parse("hello }").unwrap_err().to_string();
+        assert!(err.contains("unmatched '}'"), "{err}");
+    }
+}
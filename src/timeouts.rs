@@ -0,0 +1,81 @@
+use directories::ProjectDirs;
+use eyre::Result;
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
+use tracing::{debug, warn};
+
+use crate::device;
+
+/// Used until we have seen a device model time out, most authenticators give up after about 30s
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long each device model waits for a touch before giving up, learned from observing requests
+/// time out and persisted across restarts
+#[derive(Debug, Default)]
+pub(crate) struct Timeouts {
+    path: Option<PathBuf>,
+    /// Timeout in milliseconds, indexed by `{vid}:{pid}`
+    learned: BTreeMap<String, u64>,
+}
+
+impl Timeouts {
+    pub(crate) fn load() -> Self {
+        let Some(path) = ProjectDirs::from("", "", "u2f-touch-detector")
+            .and_then(|dirs| Some(dirs.state_dir()?.join("timeouts.toml")))
+        else {
+            warn!("cannot get state directory, learned timeouts will not be saved");
+            return Self::default();
+        };
+
+        let learned = match std::fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents).unwrap_or_else(|err| {
+                warn!(?path, "ignoring invalid learned timeouts: {err}");
+                BTreeMap::new()
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => {
+                warn!(?path, "cannot read learned timeouts: {err}");
+                BTreeMap::new()
+            }
+        };
+
+        Self {
+            path: Some(path),
+            learned,
+        }
+    }
+
+    pub(crate) fn get(&self, device: &device::Info) -> Option<Duration> {
+        self.learned
+            .get(&key(device))
+            .copied()
+            .map(Duration::from_millis)
+    }
+
+    /// Record that a request to `device` timed out after `duration`
+    pub(crate) fn record(&mut self, device: &device::Info, duration: Duration) {
+        // round to the nearest second, we detect the end of the request a little bit late
+        let millis = (duration.as_millis() as u64 + 500) / 1000 * 1000;
+        if self.learned.insert(key(device), millis) == Some(millis) {
+            return;
+        }
+        debug!(device.serial = %device.serial, ?duration, "learned new timeout");
+        if let Err(err) = self.save() {
+            warn!("failed to save learned timeouts: {err:?}");
+        }
+    }
+
+    #[culpa::try_fn]
+    fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, toml::to_string(&self.learned)?)?;
+    }
+}
+
+fn key(device: &device::Info) -> String {
+    format!("{:04x}:{:04x}", device.vendor_id, device.product_id)
+}
@@ -0,0 +1,566 @@
+use eyre::Result;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::{debug, info, warn};
+use zbus::{
+    fdo,
+    object_server::SignalContext,
+    zvariant::{ObjectPath, OwnedValue, Value},
+};
+
+use crate::{
+    device,
+    event::{self, Event},
+    metrics, models,
+};
+
+const ITEM_PATH: &str = "/StatusNotifierItem";
+const MENU_PATH: &str = "/MenuBar";
+const WATCHER: &str = "org.kde.StatusNotifierWatcher";
+
+/// Icon name, icon pixmaps, title and description
+type ToolTip = (String, Vec<(i32, i32, Vec<u8>)>, String, String);
+
+/// Menu item id, properties and child layouts
+type Layout = (i32, HashMap<String, OwnedValue>, Vec<OwnedValue>);
+
+#[derive(confique::Config, Debug)]
+#[config(partial_attr(derive(Clone, Debug)))]
+#[config(partial_attr(serde(deny_unknown_fields, rename_all = "kebab-case")))]
+pub struct Config {
+    /// Enable module
+    #[config(default = false)]
+    pub enable: bool,
+
+    /// Icon theme name shown while no touch is needed
+    #[config(default = "security-high")]
+    icon: String,
+
+    /// Icon theme name shown while a touch is needed
+    #[config(default = "dialog-warning")]
+    attention_icon: String,
+
+    /// How long the snooze menu entry suppresses notifications for, the entry is only shown when
+    /// the notify output is enabled
+    #[config(default = "15m", deserialize_with = crate::config::duration)]
+    snooze_duration: Duration,
+}
+
+#[derive(Debug)]
+struct Device {
+    info: Arc<device::Info>,
+    /// Fetched in the background after the device is added
+    firmware: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    devices: BTreeMap<Arc<str>, Device>,
+    pending: BTreeSet<Arc<str>>,
+    /// Incremented whenever the menu changes
+    revision: u32,
+}
+
+struct Item {
+    config: Arc<Config>,
+    state: Arc<Mutex<State>>,
+}
+
+#[zbus::interface(name = "org.kde.StatusNotifierItem")]
+impl Item {
+    #[zbus(property)]
+    fn category(&self) -> &str {
+        "Hardware"
+    }
+
+    #[zbus(property)]
+    fn id(&self) -> &str {
+        "u2f-touch-detector"
+    }
+
+    #[zbus(property)]
+    fn title(&self) -> &str {
+        "U2F Touch Detector"
+    }
+
+    #[zbus(property)]
+    fn status(&self) -> &str {
+        if self.state.lock().unwrap().pending.is_empty() {
+            "Active"
+        } else {
+            "NeedsAttention"
+        }
+    }
+
+    #[zbus(property)]
+    fn icon_name(&self) -> &str {
+        &self.config.icon
+    }
+
+    #[zbus(property)]
+    fn attention_icon_name(&self) -> &str {
+        &self.config.attention_icon
+    }
+
+    #[zbus(property)]
+    fn tool_tip(&self) -> ToolTip {
+        let state = self.state.lock().unwrap();
+        let description = if state.pending.is_empty() {
+            "No touch needed".to_owned()
+        } else {
+            let pending: Vec<_> = state.pending.iter().map(|id| &**id).collect();
+            format!("Touch needed on {}", pending.join(", "))
+        };
+        (
+            String::new(),
+            Vec::new(),
+            "U2F Touch Detector".to_owned(),
+            description,
+        )
+    }
+
+    #[zbus(property)]
+    fn item_is_menu(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn menu(&self) -> ObjectPath<'_> {
+        ObjectPath::from_static_str_unchecked(MENU_PATH)
+    }
+
+    fn activate(&self, _x: i32, _y: i32) {}
+
+    fn secondary_activate(&self, _x: i32, _y: i32) {}
+
+    fn context_menu(&self, _x: i32, _y: i32) {}
+
+    fn scroll(&self, _delta: i32, _orientation: &str) {}
+
+    #[zbus(signal)]
+    async fn new_status(ctxt: &SignalContext<'_>, status: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn new_tool_tip(ctxt: &SignalContext<'_>) -> zbus::Result<()>;
+}
+
+/// Menu entries for each device, menu item ids are `device index * 10 + entry`
+#[derive(Debug, Clone, Copy)]
+enum Entry {
+    Serial = 1,
+    Firmware = 2,
+    Snooze = 3,
+    Identify = 4,
+}
+
+impl Entry {
+    const ALL: [Self; 4] = [Self::Serial, Self::Firmware, Self::Snooze, Self::Identify];
+}
+
+/// A menu item with its properties and children
+struct MenuItem {
+    id: i32,
+    properties: HashMap<String, OwnedValue>,
+    children: Vec<MenuItem>,
+}
+
+impl MenuItem {
+    fn new(id: i32, properties: impl IntoIterator<Item = (&'static str, Value<'static>)>) -> Self {
+        let properties = properties
+            .into_iter()
+            .map(|(name, value)| (name.to_owned(), OwnedValue::try_from(value).unwrap()))
+            .collect();
+        Self {
+            id,
+            properties,
+            children: Vec::new(),
+        }
+    }
+
+    /// This item or its descendant with `id`
+    fn find(self, id: i32) -> Option<Self> {
+        if self.id == id {
+            return Some(self);
+        }
+        self.children.into_iter().find_map(|child| child.find(id))
+    }
+
+    /// The `(ia{sv}av)` layout of this item and `depth` levels of children, all if negative
+    fn layout(self, depth: i32) -> Layout {
+        let children = match depth {
+            0 => Vec::new(),
+            _ => self
+                .children
+                .into_iter()
+                .map(|child| OwnedValue::try_from(Value::from(child.layout(depth - 1))).unwrap())
+                .collect(),
+        };
+        (self.id, self.properties, children)
+    }
+}
+
+struct Menu {
+    config: Arc<Config>,
+    state: Arc<Mutex<State>>,
+    tx: event::Sender,
+    /// Whether the notify output is running to handle snoozing
+    snooze_supported: bool,
+}
+
+impl Menu {
+    fn root(&self) -> MenuItem {
+        let state = self.state.lock().unwrap();
+        let mut root = MenuItem::new(0, [("children-display", Value::from("submenu"))]);
+
+        if state.devices.is_empty() {
+            root.children.push(MenuItem::new(
+                1,
+                [
+                    ("label", Value::from("No devices detected")),
+                    ("enabled", Value::from(false)),
+                ],
+            ));
+        }
+
+        for (index, device) in state.devices.values().enumerate() {
+            let base = (index as i32 + 1) * 10;
+            let info = &device.info;
+            let name = models::find(info).map_or(&*info.product, |model| model.name);
+            let mut item = MenuItem::new(
+                base,
+                [
+                    ("label", Value::from(name.to_owned())),
+                    ("children-display", Value::from("submenu")),
+                ],
+            );
+            for entry in Entry::ALL {
+                if matches!(entry, Entry::Snooze) && !self.snooze_supported {
+                    continue;
+                }
+                let (label, enabled) = match entry {
+                    Entry::Serial => (format!("Serial: {}", info.serial), false),
+                    Entry::Firmware => {
+                        let firmware = device.firmware.as_deref().unwrap_or("unknown");
+                        (format!("Firmware: {firmware}"), false)
+                    }
+                    Entry::Snooze => ("Snooze notifications".to_owned(), true),
+                    Entry::Identify => ("Identify".to_owned(), true),
+                };
+                item.children.push(MenuItem::new(
+                    base + entry as i32,
+                    [
+                        ("label", Value::from(label)),
+                        ("enabled", Value::from(enabled)),
+                    ],
+                ));
+            }
+            root.children.push(item);
+        }
+
+        root
+    }
+
+    fn clicked(&self, id: i32) {
+        let Some(entry) = Entry::ALL
+            .into_iter()
+            .find(|entry| *entry as i32 == id % 10)
+        else {
+            return;
+        };
+        let state = self.state.lock().unwrap();
+        let Some(device) = usize::try_from(id / 10 - 1)
+            .ok()
+            .and_then(|index| state.devices.values().nth(index))
+        else {
+            return;
+        };
+        let device = device.info.clone();
+
+        match entry {
+            Entry::Snooze if self.snooze_supported => {
+                let duration = self.config.snooze_duration;
+                let _ = self.tx.send(Event::SnoozeRequested { device, duration });
+            }
+            Entry::Identify => {
+                std::thread::spawn(move || {
+                    if let Err(err) = device.wink() {
+                        warn!("failed to identify device: {err:?}");
+                    }
+                });
+            }
+            Entry::Snooze | Entry::Serial | Entry::Firmware => {}
+        }
+    }
+}
+
+#[zbus::interface(name = "com.canonical.dbusmenu")]
+impl Menu {
+    fn get_layout(
+        &self,
+        parent_id: i32,
+        recursion_depth: i32,
+        _property_names: Vec<String>,
+    ) -> fdo::Result<(u32, Layout)> {
+        let revision = self.state.lock().unwrap().revision;
+        let parent = self
+            .root()
+            .find(parent_id)
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("unknown menu item {parent_id}")))?;
+        Ok((revision, parent.layout(recursion_depth)))
+    }
+
+    fn get_group_properties(
+        &self,
+        ids: Vec<i32>,
+        _property_names: Vec<String>,
+    ) -> Vec<(i32, HashMap<String, OwnedValue>)> {
+        ids.into_iter()
+            .filter_map(|id| Some((id, self.root().find(id)?.properties)))
+            .collect()
+    }
+
+    fn get_property(&self, id: i32, name: &str) -> fdo::Result<OwnedValue> {
+        self.root()
+            .find(id)
+            .and_then(|mut item| item.properties.remove(name))
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("unknown property {name} of {id}")))
+    }
+
+    fn event(&self, id: i32, event_id: &str, _data: OwnedValue, _timestamp: u32) {
+        if event_id == "clicked" {
+            self.clicked(id);
+        }
+    }
+
+    fn event_group(&self, events: Vec<(i32, String, OwnedValue, u32)>) -> Vec<i32> {
+        for (id, event_id, _, _) in events {
+            if event_id == "clicked" {
+                self.clicked(id);
+            }
+        }
+        Vec::new()
+    }
+
+    fn about_to_show(&self, _id: i32) -> bool {
+        false
+    }
+
+    fn about_to_show_group(&self, _ids: Vec<i32>) -> (Vec<i32>, Vec<i32>) {
+        (Vec::new(), Vec::new())
+    }
+
+    #[zbus(property)]
+    fn version(&self) -> u32 {
+        3
+    }
+
+    #[zbus(property)]
+    fn text_direction(&self) -> &str {
+        "ltr"
+    }
+
+    #[zbus(property)]
+    fn status(&self) -> &str {
+        "normal"
+    }
+
+    #[zbus(property)]
+    fn icon_theme_path(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    #[zbus(signal)]
+    async fn layout_updated(
+        ctxt: &SignalContext<'_>,
+        revision: u32,
+        parent: i32,
+    ) -> zbus::Result<()>;
+}
+
+#[culpa::try_fn]
+pub(crate) fn run(
+    config: Config,
+    mut rx: event::Receiver,
+    tx: event::Sender,
+    snooze_supported: bool,
+) -> Result<()> {
+    let config = Arc::new(config);
+    let shared = Arc::new(Mutex::new(State::default()));
+
+    let name = format!("org.kde.StatusNotifierItem-{}-1", std::process::id());
+    let item = Item {
+        config: config.clone(),
+        state: shared.clone(),
+    };
+    let menu = Menu {
+        config: config.clone(),
+        state: shared.clone(),
+        tx,
+        snooze_supported,
+    };
+    let connection = zbus::blocking::connection::Builder::session()?
+        .name(name.as_str())?
+        .serve_at(ITEM_PATH, item)?
+        .serve_at(MENU_PATH, menu)?
+        .build()?;
+
+    std::thread::spawn({
+        let connection = connection.clone();
+        move || {
+            if let Err(err) = register(&connection, &name) {
+                warn!("cannot register tray icon: {err:?}");
+            }
+        }
+    });
+
+    let item_ctxt = SignalContext::new(connection.inner(), ITEM_PATH)?;
+    let menu_ctxt = SignalContext::new(connection.inner(), MENU_PATH)?;
+
+    while let Some(event) = event::recv(&mut rx) {
+        let device = event.device();
+        let mut state = shared.lock().unwrap();
+        let (item_changed, menu_changed) = match &event {
+            Event::TouchNeeded { .. } => (state.pending.insert(device.id()), false),
+            Event::TouchFinished { .. } => (state.pending.remove(&device.id()), false),
+            Event::DeviceAdded { .. } => {
+                let device = Device {
+                    info: device.clone(),
+                    firmware: None,
+                };
+                state.devices.insert(device.info.id(), device);
+                fetch_firmware(event.device().clone(), shared.clone(), menu_ctxt.clone());
+                (false, true)
+            }
+            Event::DeviceRemoved { .. } => {
+                state.devices.remove(&device.id());
+                (state.pending.remove(&device.id()), true)
+            }
+            Event::SnoozeRequested { .. } => (false, false),
+        };
+        if menu_changed {
+            state.revision += 1;
+        }
+        let (revision, waiting) = (state.revision, !state.pending.is_empty());
+        drop(state);
+
+        let result = zbus::block_on(async {
+            if item_changed {
+                let status = if waiting { "NeedsAttention" } else { "Active" };
+                Item::new_status(&item_ctxt, status).await?;
+                Item::new_tool_tip(&item_ctxt).await?;
+            }
+            if menu_changed {
+                Menu::layout_updated(&menu_ctxt, revision, 0).await?;
+            }
+            zbus::Result::Ok(())
+        });
+        if let Err(err) = result {
+            metrics::OUTPUT_FAILURES.increment(&["tray"]);
+            warn!("failed to emit tray signal: {err:?}");
+        }
+    }
+}
+
+/// Query the device firmware version in the background, then update the menu
+fn fetch_firmware(
+    info: Arc<device::Info>,
+    shared: Arc<Mutex<State>>,
+    menu_ctxt: SignalContext<'static>,
+) {
+    std::thread::spawn(move || {
+        let firmware = match info.firmware() {
+            Ok(firmware) => firmware,
+            Err(err) => {
+                debug!(device.serial = %info.serial, "cannot get firmware version: {err:?}");
+                return;
+            }
+        };
+        let revision = {
+            let mut state = shared.lock().unwrap();
+            let Some(device) = state.devices.get_mut(&info.id()) else {
+                return;
+            };
+            device.firmware = Some(firmware);
+            state.revision += 1;
+            state.revision
+        };
+        if let Err(err) = zbus::block_on(Menu::layout_updated(&menu_ctxt, revision, 0)) {
+            metrics::OUTPUT_FAILURES.increment(&["tray"]);
+            warn!("failed to emit tray signal: {err:?}");
+        }
+    });
+}
+
+/// Register with the status notifier watcher, and again each time it restarts (e.g. when the
+/// panel restarts)
+#[culpa::try_fn]
+fn register(connection: &zbus::blocking::Connection, name: &str) -> Result<()> {
+    let watcher =
+        zbus::blocking::Proxy::new(connection, WATCHER, "/StatusNotifierWatcher", WATCHER)?;
+    let register = || match watcher.call_method("RegisterStatusNotifierItem", &(name,)) {
+        Ok(_) => info!("registered tray icon"),
+        Err(err) => warn!("cannot register tray icon, waiting for a tray: {err}"),
+    };
+    register();
+
+    let rule = zbus::MatchRule::builder()
+        .msg_type(zbus::message::Type::Signal)
+        .sender("org.freedesktop.DBus")?
+        .interface("org.freedesktop.DBus")?
+        .member("NameOwnerChanged")?
+        .arg(0, WATCHER)?
+        .build();
+    for message in zbus::blocking::MessageIterator::for_match_rule(rule, connection, None)? {
+        let (_, _, new_owner): (String, String, String) = message?.body().deserialize()?;
+        if !new_owner.is_empty() {
+            register();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn menu(snooze_supported: bool) -> Menu {
+        let config = Config {
+            enable: true,
+            icon: "security-high".to_owned(),
+            attention_icon: "dialog-warning".to_owned(),
+            snooze_duration: Duration::from_secs(60),
+        };
+        let info = crate::event::testing::device();
+        let mut state = State::default();
+        state.devices.insert(
+            info.serial.clone(),
+            Device {
+                info,
+                firmware: None,
+            },
+        );
+        Menu {
+            config: Arc::new(config),
+            state: Arc::new(Mutex::new(state)),
+            tx: tokio::sync::broadcast::channel(16).0,
+            snooze_supported,
+        }
+    }
+
+    fn entries(menu: &Menu) -> Vec<i32> {
+        let root = menu.root();
+        root.children[0]
+            .children
+            .iter()
+            .map(|item| item.id)
+            .collect()
+    }
+
+    #[test]
+    fn snooze_only_with_notify() {
+        assert_eq!(entries(&menu(true)), [11, 12, 13, 14]);
+        assert_eq!(entries(&menu(false)), [11, 12, 14]);
+    }
+}
@@ -0,0 +1,276 @@
+use crate::{
+    event,
+    http::{self, Request},
+    metrics,
+};
+use eyre::{ensure, Result};
+use serde::Serialize;
+use std::{
+    collections::BTreeSet,
+    io::Write,
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info, info_span, warn};
+
+/// How often to send event stream clients a comment, so a client that went away is noticed
+/// without waiting for the next event
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+/// How long a client may take to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(confique::Config, Debug)]
+#[config(partial_attr(derive(Clone, Debug)))]
+#[config(partial_attr(serde(deny_unknown_fields, rename_all = "kebab-case")))]
+pub struct Config {
+    /// Enable module
+    #[config(default = false)]
+    pub enable: bool,
+
+    /// Address to serve `/events` and `/status` on, must be a loopback address
+    #[config(default = "127.0.0.1:8157")]
+    listen: SocketAddr,
+
+    /// Value of the `Access-Control-Allow-Origin` header, e.g. to allow a browser extension
+    allow_origin: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Status {
+    pending: BTreeSet<Arc<str>>,
+}
+
+#[derive(Debug, Serialize)]
+struct Touch<'a> {
+    /// The serial, or for devices without one their vendor id, product id and hidraw device
+    id: &'a str,
+    serial: &'a str,
+    needed: bool,
+}
+
+#[culpa::try_fn]
+pub(crate) fn run(config: Config, mut rx: event::Receiver) -> Result<()> {
+    ensure!(
+        config.listen.ip().is_loopback(),
+        "web output must listen on a loopback address, not {}",
+        config.listen,
+    );
+
+    let status = Arc::new(Mutex::new(Status::default()));
+    let (tx, _) = tokio::sync::broadcast::channel(16);
+
+    std::thread::spawn({
+        let tx = tx.clone();
+        let status = status.clone();
+        move || {
+            while let Some(event) = event::recv(&mut rx) {
+                let Some(needed) = event.needed() else {
+                    continue;
+                };
+                let device = event.device();
+                let id = device.id();
+                let mut status = status.lock().unwrap();
+                let changed = if needed {
+                    status.pending.insert(id.clone())
+                } else {
+                    status.pending.remove(&id)
+                };
+                if changed {
+                    let touch = Touch {
+                        id: &id,
+                        serial: &device.serial,
+                        needed,
+                    };
+                    let event = format!(
+                        "event: touch\ndata: {}\n\n",
+                        serde_json::to_string(&touch).unwrap()
+                    );
+                    let _ = tx.send(Arc::<str>::from(event));
+                }
+            }
+        }
+    });
+
+    std::thread::spawn({
+        let tx = tx.clone();
+        move || loop {
+            std::thread::sleep(KEEP_ALIVE);
+            let _ = tx.send(Arc::<str>::from(": keep-alive\n\n"));
+        }
+    });
+
+    let listener = TcpListener::bind(config.listen)?;
+    info!(listen = %config.listen, "listening for web clients");
+
+    let config = Arc::new(config);
+    let mut connection_ids = 0..u64::MAX;
+    for stream in listener.incoming() {
+        let connection_id = connection_ids
+            .next()
+            .expect("aint nobody gonna service 2^64 connections");
+        let span = info_span!("connection", connection_id);
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("failed to accept web client: {err:?}");
+                continue;
+            }
+        };
+        std::thread::spawn({
+            let config = config.clone();
+            let status = status.clone();
+            let rx = tx.subscribe();
+            move || {
+                let _guard = span.entered();
+                if let Err(err) = handle(&config, stream, &status, rx) {
+                    debug!("web client errored: {err:?}");
+                }
+            }
+        });
+    }
+}
+
+#[culpa::try_fn]
+fn handle(
+    config: &Config,
+    mut stream: TcpStream,
+    status: &Mutex<Status>,
+    mut rx: tokio::sync::broadcast::Receiver<Arc<str>>,
+) -> Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let request = Request::read_from(&mut stream)?;
+    debug!(?request, "web request");
+
+    let mut headers = Vec::new();
+    if let Some(origin) = &config.allow_origin {
+        headers.push(("Access-Control-Allow-Origin", origin.as_str()));
+    }
+
+    if !request
+        .host
+        .as_deref()
+        .is_some_and(|host| allowed_host(config.listen, host))
+    {
+        info!(
+            host = request.host,
+            "rejecting web request for another host"
+        );
+        http::write_response(&mut stream, "403 Forbidden", &headers, Some(b""))?;
+        return;
+    }
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/status") => {
+            let body = serde_json::to_vec(&*status.lock().unwrap())?;
+            headers.push(("Content-Type", "application/json"));
+            http::write_response(&mut stream, "200 OK", &headers, Some(&body))?;
+        }
+        ("GET", "/events") => {
+            headers.push(("Content-Type", "text/event-stream"));
+            http::write_response(&mut stream, "200 OK", &headers, None)?;
+            info!("web client subscribed to events");
+
+            // send the current state first so clients connecting mid-request still see it
+            write_snapshot(&mut stream, status)?;
+
+            loop {
+                let event = match rx.blocking_recv() {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(count)) => {
+                        // the missed events can't be replayed, so resync the client instead
+                        debug!(count, "web client lagged behind, sending status");
+                        write_snapshot(&mut stream, status)?;
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                match stream.write_all(event.as_bytes()) {
+                    Ok(()) => (),
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            std::io::ErrorKind::BrokenPipe | std::io::ErrorKind::ConnectionReset
+                        ) =>
+                    {
+                        info!("web client closed");
+                        break;
+                    }
+                    Err(e) => {
+                        metrics::OUTPUT_FAILURES.increment(&["web"]);
+                        warn!("error writing to web client: {e:?}");
+                        break;
+                    }
+                }
+            }
+        }
+        (_, "/status" | "/events") => {
+            headers.push(("Allow", "GET"));
+            http::write_response(&mut stream, "405 Method Not Allowed", &headers, Some(b""))?;
+        }
+        _ => {
+            http::write_response(&mut stream, "404 Not Found", &headers, Some(b""))?;
+        }
+    }
+}
+
+/// Whether the request is addressed to this loopback server, any other host means a web page is
+/// trying to reach it through DNS rebinding
+fn allowed_host(listen: SocketAddr, host: &str) -> bool {
+    // an IPv6 address without a port ends with its closing bracket
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if !host.ends_with(']') => {
+            if port.parse() != Ok(listen.port()) {
+                return false;
+            }
+            name
+        }
+        _ => host,
+    };
+    let ip = match listen.ip() {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{ip}]"),
+    };
+    ["localhost", "127.0.0.1", "[::1]", &ip]
+        .iter()
+        .any(|allowed| name.eq_ignore_ascii_case(allowed))
+}
+
+#[culpa::try_fn]
+fn write_snapshot(stream: &mut TcpStream, status: &Mutex<Status>) -> Result<()> {
+    let snapshot = serde_json::to_string(&*status.lock().unwrap())?;
+    write!(stream, "event: status\ndata: {snapshot}\n\n")?;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_loopback_hosts() {
+        let listen = "127.0.0.1:8157".parse().unwrap();
+        for host in [
+            "localhost:8157",
+            "LocalHost:8157",
+            "127.0.0.1:8157",
+            "[::1]:8157",
+            "localhost",
+        ] {
+            assert!(allowed_host(listen, host), "{host}");
+        }
+        for host in [
+            "evil.example:8157",
+            "localhost:80",
+            "127.0.0.1:",
+            "[::1]:81",
+            "localhost.evil.example",
+        ] {
+            assert!(!allowed_host(listen, host), "{host}");
+        }
+
+        let listen = "127.0.0.2:8000".parse().unwrap();
+        assert!(allowed_host(listen, "127.0.0.2:8000"));
+        assert!(!allowed_host(listen, "127.0.0.2:8157"));
+    }
+}